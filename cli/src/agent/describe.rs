@@ -0,0 +1,22 @@
+use clap::Args;
+use engine::config::NatsConfig;
+
+use super::open_registry;
+
+#[derive(Args)]
+pub struct DescribeArgs {
+    /// Agent id
+    id: String,
+
+    #[command(flatten)]
+    nats_config: NatsConfig,
+}
+
+pub async fn run_describe_command(args: DescribeArgs) -> anyhow::Result<()> {
+    let registry = open_registry(&args.nats_config).await?;
+    let record = registry.get(&args.id).await?;
+
+    println!("{}", serde_json::to_string_pretty(&record)?);
+
+    Ok(())
+}
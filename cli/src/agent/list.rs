@@ -0,0 +1,58 @@
+use clap::Args;
+use engine::config::NatsConfig;
+
+use super::open_registry;
+
+#[derive(Args)]
+pub struct ListArgs {
+    /// Only list agents running this workflow
+    #[arg(long)]
+    workflow: Option<String>,
+
+    #[command(flatten)]
+    nats_config: NatsConfig,
+}
+
+pub async fn run_list_command(args: ListArgs) -> anyhow::Result<()> {
+    let registry = open_registry(&args.nats_config).await?;
+
+    for record in registry.list().await? {
+        if let Some(workflow) = &args.workflow {
+            if !record.workflows.iter().any(|w| &w.name == workflow) {
+                continue;
+            }
+        }
+
+        let workflows = record
+            .workflows
+            .iter()
+            .map(|workflow| format!("{} v{}", workflow.name, workflow.version))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let mut labels = record
+            .labels
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>();
+        labels.sort();
+
+        println!(
+            "{}  {}  {}  {}  {}  in flight {}  {}{}",
+            record.id,
+            record.hostname,
+            record.version,
+            workflows,
+            if labels.is_empty() {
+                String::from("-")
+            } else {
+                labels.join(",")
+            },
+            record.load.executions_in_flight,
+            record.heartbeat_at.to_rfc3339(),
+            if record.is_stale() { "  stale" } else { "" },
+        );
+    }
+
+    Ok(())
+}
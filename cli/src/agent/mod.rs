@@ -1,8 +1,15 @@
 use clap::{Args, Subcommand};
+use engine::config::NatsConfig;
 
 mod start;
 use start::*;
 
+mod list;
+use list::*;
+
+mod describe;
+use describe::*;
+
 #[derive(Args)]
 pub struct AgentArgs {
     #[command(subcommand)]
@@ -13,10 +20,27 @@ pub struct AgentArgs {
 enum AgentCommands {
     /// Start a deadlift agent
     Start(StartArgs),
+
+    /// List running agents and their workflows
+    List(ListArgs),
+
+    /// Show the latest heartbeat of an agent
+    Describe(DescribeArgs),
 }
 
 pub async fn run_agent_command(agent_args: AgentArgs) -> anyhow::Result<()> {
     match agent_args.command {
         AgentCommands::Start(args) => run_start_command(args).await,
+        AgentCommands::List(args) => run_list_command(args).await,
+        AgentCommands::Describe(args) => run_describe_command(args).await,
     }
 }
+
+async fn open_registry(
+    nats_config: &NatsConfig,
+) -> anyhow::Result<engine::registry::AgentRegistry> {
+    let nc = nats_config.connect().await?;
+    let js = async_nats::jetstream::new(nc);
+
+    engine::registry::AgentRegistry::open(&js).await
+}
@@ -1,7 +1,4 @@
-use std::time::Duration;
-
 use clap::Args;
-use crossbeam_channel::{bounded, select, tick, Receiver};
 use engine::config::EngineConfig;
 use serde::Serialize;
 
@@ -9,36 +6,24 @@ use serde::Serialize;
 pub struct StartArgs {
     #[command(flatten)]
     config: EngineConfig,
-}
-
-pub async fn run_start_command(args: StartArgs) -> anyhow::Result<()> {
-    let mut config_buffer = vec![];
-    serde_yaml::to_writer(&mut config_buffer, &args.config)?;
-
-    let handles = engine::run(config_buffer).await?; // FIXME-- define default agent config
 
-    let ctrl_c_events = ctrl_channel().expect("ctrl c events");
-    let ticks = tick(Duration::from_secs(5));
-
-    loop {
-        select! {
-            recv(ticks) -> _ => {},
-            recv(ctrl_c_events) -> _ => {
-                if let Some(v) = handles.execution_handle_opt { v.abort() }
-                break;
-            }
-            // default => {}
-        }
-    }
+    /// Agent label matched against workflow placement, as `key=value`; repeatable
+    #[arg(long = "label", value_parser = parse_label)]
+    #[serde(skip)]
+    labels: Vec<(String, String)>,
+}
 
-    Ok(())
+fn parse_label(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| String::from("expected key=value"))
 }
 
-fn ctrl_channel() -> Result<Receiver<()>, ctrlc::Error> {
-    let (sender, receiver) = bounded(0);
-    ctrlc::set_handler(move || {
-        let _ = sender.send(());
-    })?;
+pub async fn run_start_command(mut args: StartArgs) -> anyhow::Result<()> {
+    args.config.agent.labels.extend(args.labels.drain(..));
+
+    let mut config_buffer = vec![];
+    serde_yaml::to_writer(&mut config_buffer, &args.config)?;
 
-    Ok(receiver)
+    engine::runtime::run_agent(config_buffer).await
 }
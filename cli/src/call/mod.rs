@@ -1,18 +1,30 @@
 use std::io::Read;
 
 use clap::Args;
-use engine::config::NatsConfig;
+use engine::{config::NatsConfig, nats::EXECUTION_ID_HEADER, subjects::Subjects};
 
 #[derive(Args)]
 pub struct CallArgs {
-    /// WASM function name
+    /// Workflow name
     #[arg(long)]
-    fn_name: String,
+    workflow: String,
+
+    /// Only run this stage, with the input as is
+    #[arg(long)]
+    stage: Option<String>,
+
+    /// Only call agents running this version of the workflow; any version when unset
+    #[arg(long)]
+    workflow_version: Option<u32>,
 
     /// Raw string input; can also be passed from stdin
     #[arg(long)]
     input: Option<String>,
 
+    /// Execution id to correlate the call with agent logs and records; generated when unset
+    #[arg(long)]
+    execution_id: Option<String>,
+
     #[command(flatten)]
     nats_config: NatsConfig,
 }
@@ -29,19 +41,35 @@ pub async fn run_call_command(args: CallArgs) -> anyhow::Result<()> {
 
     let nc = args.nats_config.connect().await?;
 
+    let execution_id = args
+        .execution_id
+        .unwrap_or_else(engine::executor::new_execution_id);
+
+    let mut headers = async_nats::HeaderMap::new();
+    headers.insert(EXECUTION_ID_HEADER, execution_id.as_str());
+
+    let subjects = Subjects::new(&args.nats_config);
+    let (target, subject) = match &args.stage {
+        Some(stage) => (
+            format!("stage '{stage}' of '{}'", args.workflow),
+            subjects.stage(&args.workflow, args.workflow_version, stage),
+        ),
+        None => (
+            format!("'{}'", args.workflow),
+            subjects.workflow(&args.workflow, args.workflow_version),
+        ),
+    };
+
     let req = async_nats::Request::new()
-        .inbox(format!("deadlift.executions.{}.reply", args.fn_name))
+        .headers(headers)
         .payload(input.into());
 
-    let response = nc
-        .send_request(format!("deadlift.executions.{}", args.fn_name), req)
-        .await?;
+    let response = nc.send_request(subject, req).await?;
 
     let response_payload: Vec<u8> = response.payload.into();
 
     println!(
-        "successfully called {}; response: {}",
-        args.fn_name,
+        "successfully called {target} (execution {execution_id}); response: {}",
         String::from_utf8_lossy(&response_payload)
     );
 
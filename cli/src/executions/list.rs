@@ -0,0 +1,41 @@
+use clap::Args;
+use engine::config::NatsConfig;
+
+use super::open_history;
+
+#[derive(Args)]
+pub struct ListArgs {
+    /// Only list executions of this workflow
+    #[arg(long)]
+    workflow: Option<String>,
+
+    /// Maximum number of executions to list
+    #[arg(long, default_value_t = 20)]
+    limit: usize,
+
+    #[command(flatten)]
+    nats_config: NatsConfig,
+}
+
+pub async fn run_list_command(args: ListArgs) -> anyhow::Result<()> {
+    let history = open_history(&args.nats_config).await?;
+    let records = history.list(args.workflow.as_deref(), args.limit).await?;
+
+    for record in records {
+        println!(
+            "{}  {}  {} v{}  {}  {}  {}",
+            record.id,
+            record.started_at.to_rfc3339(),
+            record.workflow,
+            record.workflow_version,
+            record.trigger,
+            record.status,
+            record
+                .duration_ms
+                .map(|ms| format!("{ms}ms"))
+                .unwrap_or_else(|| String::from("-")),
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use clap::{Args, Subcommand};
+use engine::config::NatsConfig;
+
+mod list;
+use list::*;
+
+mod show;
+use show::*;
+
+mod replay;
+use replay::*;
+
+#[derive(Args)]
+pub struct ExecutionsArgs {
+    #[command(subcommand)]
+    command: ExecutionsCommands,
+}
+
+#[derive(Subcommand)]
+enum ExecutionsCommands {
+    /// List recent workflow executions
+    List(ListArgs),
+
+    /// Show an execution and its stages
+    Show(ShowArgs),
+
+    /// Re-run an execution, or its stages from one onwards, with the retained inputs
+    Replay(ReplayArgs),
+}
+
+pub async fn run_executions_command(executions_args: ExecutionsArgs) -> anyhow::Result<()> {
+    match executions_args.command {
+        ExecutionsCommands::List(args) => run_list_command(args).await,
+        ExecutionsCommands::Show(args) => run_show_command(args).await,
+        ExecutionsCommands::Replay(args) => run_replay_command(args).await,
+    }
+}
+
+async fn open_history(nats_config: &NatsConfig) -> anyhow::Result<engine::history::HistoryReader> {
+    let nc = nats_config.connect().await?;
+    let js = async_nats::jetstream::new(nc);
+
+    engine::history::HistoryReader::open(&js).await
+}
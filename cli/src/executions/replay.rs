@@ -0,0 +1,55 @@
+use clap::Args;
+use engine::{
+    config::NatsConfig,
+    history::HistoryReader,
+    nats::{REPLAY_FROM_STAGE_HEADER, REPLAY_OF_HEADER},
+    subjects::Subjects,
+};
+
+#[derive(Args)]
+pub struct ReplayArgs {
+    /// Id of the execution to replay
+    id: String,
+
+    /// Only run this stage and its downstream stages, starting from the stage's recorded input
+    #[arg(long)]
+    from_stage: Option<String>,
+
+    /// Only replay on agents running this version of the workflow
+    #[arg(long)]
+    workflow_version: Option<u32>,
+
+    #[command(flatten)]
+    nats_config: NatsConfig,
+}
+
+pub async fn run_replay_command(args: ReplayArgs) -> anyhow::Result<()> {
+    let nc = args.nats_config.connect().await?;
+    let js = async_nats::jetstream::new(nc.clone());
+
+    let record = HistoryReader::open(&js).await?.get(&args.id).await?;
+
+    let mut headers = async_nats::HeaderMap::new();
+    headers.insert(REPLAY_OF_HEADER, args.id.as_str());
+    if let Some(stage) = &args.from_stage {
+        headers.insert(REPLAY_FROM_STAGE_HEADER, stage.as_str());
+    }
+
+    let req = async_nats::Request::new()
+        .headers(headers)
+        .payload(Default::default());
+
+    let subject =
+        Subjects::new(&args.nats_config).workflow(&record.workflow, args.workflow_version);
+    let response = nc.send_request(subject, req).await?;
+
+    let response_payload: Vec<u8> = response.payload.into();
+
+    println!(
+        "successfully replayed {}; response: {}",
+        args.id,
+        String::from_utf8_lossy(&response_payload)
+    );
+
+    Ok(())
+}
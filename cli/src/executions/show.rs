@@ -0,0 +1,22 @@
+use clap::Args;
+use engine::config::NatsConfig;
+
+use super::open_history;
+
+#[derive(Args)]
+pub struct ShowArgs {
+    /// Execution id
+    id: String,
+
+    #[command(flatten)]
+    nats_config: NatsConfig,
+}
+
+pub async fn run_show_command(args: ShowArgs) -> anyhow::Result<()> {
+    let history = open_history(&args.nats_config).await?;
+    let record = history.get(&args.id).await?;
+
+    println!("{}", serde_json::to_string_pretty(&record)?);
+
+    Ok(())
+}
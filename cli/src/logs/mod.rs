@@ -0,0 +1,22 @@
+use clap::{Args, Subcommand};
+
+mod tail;
+use tail::*;
+
+#[derive(Args)]
+pub struct LogsArgs {
+    #[command(subcommand)]
+    command: LogsCommands,
+}
+
+#[derive(Subcommand)]
+enum LogsCommands {
+    /// Stream plugin and engine logs published by agents
+    Tail(TailArgs),
+}
+
+pub async fn run_logs_command(logs_args: LogsArgs) -> anyhow::Result<()> {
+    match logs_args.command {
+        LogsCommands::Tail(args) => run_tail_command(args).await,
+    }
+}
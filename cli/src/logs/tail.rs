@@ -0,0 +1,191 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_nats::jetstream::consumer::{pull, DeliverPolicy};
+use clap::Args;
+use engine::{
+    config::NatsConfig,
+    logs::{LogRecord, LOG_STREAM_NAME},
+    subjects::Subjects,
+};
+use futures::StreamExt;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+#[derive(Args)]
+pub struct TailArgs {
+    /// Only show logs of this workflow
+    #[arg(long)]
+    workflow: Option<String>,
+
+    /// Least severe level to show; one of error, warn, info, debug or trace
+    #[arg(long, default_value_t = String::from("trace"))]
+    level: String,
+
+    /// Only show logs of this stage
+    #[arg(long)]
+    stage: Option<String>,
+
+    /// Only show logs of this execution
+    #[arg(long)]
+    execution_id: Option<String>,
+
+    /// Start from logs this long ago, e.g. `30s`, `10m`, `2h` or `1d`, or from an RFC 3339 time
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Print without ANSI colors
+    #[arg(long)]
+    no_color: bool,
+
+    #[command(flatten)]
+    nats_config: NatsConfig,
+}
+
+pub async fn run_tail_command(args: TailArgs) -> anyhow::Result<()> {
+    let max_rank =
+        level_rank(&args.level).ok_or_else(|| anyhow!("unknown log level '{}'", args.level))?;
+
+    let deliver_policy = match &args.since {
+        Some(since) => DeliverPolicy::ByStartTime {
+            start_time: parse_since(since)?,
+        },
+        None => DeliverPolicy::New,
+    };
+
+    let subjects = Subjects::new(&args.nats_config);
+    let nc = args.nats_config.connect().await?;
+    let js = async_nats::jetstream::new(nc);
+
+    let mut messages = js
+        .get_stream(LOG_STREAM_NAME)
+        .await
+        .map_err(|e| anyhow!("failed to open the logs stream; {e}"))?
+        .create_consumer(pull::OrderedConfig {
+            filter_subject: args
+                .workflow
+                .as_deref()
+                .map(|workflow| subjects.logs(workflow))
+                .unwrap_or_else(|| subjects.all_logs()),
+            deliver_policy,
+            ..Default::default()
+        })
+        .await?
+        .messages()
+        .await?;
+
+    while let Some(msg) = messages.next().await {
+        let Ok(record) = serde_json::from_slice::<LogRecord>(&msg?.payload) else {
+            continue;
+        };
+
+        if level_rank(&record.level).is_some_and(|rank| rank > max_rank)
+            || args
+                .stage
+                .as_ref()
+                .is_some_and(|stage| record.stage.as_ref() != Some(stage))
+            || args
+                .execution_id
+                .as_ref()
+                .is_some_and(|id| record.execution_id.as_ref() != Some(id))
+        {
+            continue;
+        }
+
+        println!("{}", format_record(&record, !args.no_color));
+    }
+
+    Ok(())
+}
+
+fn format_record(record: &LogRecord, color: bool) -> String {
+    let level = format!("{:>5}", record.level);
+    let level = match (color, level_color(&record.level)) {
+        (true, Some(code)) => format!("\x1b[{code}m{level}\x1b[0m"),
+        _ => level,
+    };
+
+    let mut source = record.workflow.clone();
+    if let Some(stage) = &record.stage {
+        source = format!("{source}/{stage}");
+    }
+
+    format!(
+        "{} {level} {source} {} {}",
+        record.timestamp.to_rfc3339(),
+        record.execution_id.as_deref().unwrap_or("-"),
+        record.message
+    )
+}
+
+/// Lower is more severe
+fn level_rank(level: &str) -> Option<u8> {
+    match level.to_ascii_lowercase().as_str() {
+        "error" => Some(0),
+        "warn" => Some(1),
+        "info" => Some(2),
+        "debug" => Some(3),
+        "trace" => Some(4),
+        _ => None,
+    }
+}
+
+fn level_color(level: &str) -> Option<u8> {
+    level_rank(level).map(|rank| [31, 33, 32, 34, 35][rank as usize])
+}
+
+fn parse_since(since: &str) -> anyhow::Result<OffsetDateTime> {
+    if let Ok(time) = OffsetDateTime::parse(since, &Rfc3339) {
+        return Ok(time);
+    }
+
+    let invalid = || anyhow!("invalid --since '{since}'; expected e.g. 10m or an RFC 3339 time");
+
+    let (split, _) = since.char_indices().last().ok_or_else(invalid)?;
+    let (value, unit) = since.split_at(split);
+    let value = value.parse::<u64>().map_err(|_| invalid())?;
+
+    let secs = match unit {
+        "s" => value,
+        "m" => value * 60,
+        "h" => value * 60 * 60,
+        "d" => value * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+
+    Ok(OffsetDateTime::now_utc() - Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_rank() {
+        assert_eq!(level_rank("ERROR"), Some(0));
+        assert_eq!(level_rank("warn"), Some(1));
+        assert_eq!(level_rank("Trace"), Some(4));
+        assert_eq!(level_rank("fatal"), None);
+
+        assert!(level_rank("error") < level_rank("info"));
+    }
+
+    #[test]
+    fn test_parse_since() {
+        let now = OffsetDateTime::now_utc();
+        let ago = |since: &str| now - parse_since(since).unwrap();
+
+        assert!((ago("90s") - time::Duration::seconds(90)).abs() < time::Duration::seconds(5));
+        assert!((ago("10m") - time::Duration::minutes(10)).abs() < time::Duration::seconds(5));
+        assert!((ago("2h") - time::Duration::hours(2)).abs() < time::Duration::seconds(5));
+        assert!((ago("1d") - time::Duration::days(1)).abs() < time::Duration::seconds(5));
+
+        assert_eq!(
+            parse_since("2024-03-01T10:00:00Z").unwrap(),
+            OffsetDateTime::parse("2024-03-01T10:00:00Z", &Rfc3339).unwrap()
+        );
+
+        for since in ["", "m", "10", "10w", "-1m", "10µ"] {
+            assert!(parse_since(since).is_err(), "{since}");
+        }
+    }
+}
@@ -14,6 +14,15 @@ use project::*;
 mod call;
 use call::*;
 
+mod run;
+use run::*;
+
+mod executions;
+use executions::*;
+
+mod logs;
+use logs::*;
+
 /// deadlift
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -35,6 +44,15 @@ enum DeadliftCommands {
 
     /// Command for interacting with deadlift modules and workflows
     Call(CallArgs),
+
+    /// Command for running deadlift workflows locally, without NATS
+    Run(RunArgs),
+
+    /// Commands for inspecting workflow execution history
+    Executions(ExecutionsArgs),
+
+    /// Commands for watching logs published by deadlift agents
+    Logs(LogsArgs),
 }
 
 #[tokio::main]
@@ -46,5 +64,10 @@ async fn main() -> anyhow::Result<()> {
         DeadliftCommands::User(user_args) => run_user_command(user_args).await,
         DeadliftCommands::Project(project_args) => run_project_command(project_args).await,
         DeadliftCommands::Call(call_args) => run_call_command(call_args).await,
+        DeadliftCommands::Run(run_args) => run_run_command(run_args).await,
+        DeadliftCommands::Executions(executions_args) => {
+            run_executions_command(executions_args).await
+        }
+        DeadliftCommands::Logs(logs_args) => run_logs_command(logs_args).await,
     }
 }
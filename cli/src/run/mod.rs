@@ -0,0 +1,58 @@
+use std::{collections::HashMap, io::Read};
+
+use clap::Args;
+use engine::config::{PluginConfig, PostgresConnectionConfig};
+
+#[derive(Args)]
+pub struct RunArgs {
+    /// Path to the workflow file; stage module paths are resolved from the current directory
+    #[arg(long, default_value_t = String::from("./workflow.yml"))]
+    workflow: String,
+
+    /// Raw string input; can also be passed from stdin
+    #[arg(long)]
+    input: Option<String>,
+
+    /// Postgres connection available to stages, as `name=url`; repeatable
+    #[arg(long, value_parser = parse_postgres_connection)]
+    postgres: Vec<(String, String)>,
+
+    #[command(flatten)]
+    plugin_config: PluginConfig,
+}
+
+fn parse_postgres_connection(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(name, url)| (name.to_string(), url.to_string()))
+        .ok_or_else(|| String::from("expected name=url"))
+}
+
+pub async fn run_run_command(args: RunArgs) -> anyhow::Result<()> {
+    let input = match args.input {
+        Some(input) => input.as_bytes().to_vec(),
+        None => {
+            let mut buffer = vec![];
+            std::io::stdin().read_to_end(&mut buffer)?;
+            buffer
+        }
+    };
+
+    let workflow_bytes = tokio::fs::read(&args.workflow).await?;
+
+    let postgres_config = args
+        .postgres
+        .into_iter()
+        .map(|(name, url)| (name, PostgresConnectionConfig::new(url)))
+        .collect::<HashMap<_, _>>();
+
+    let output =
+        engine::run_local(workflow_bytes, &args.plugin_config, &postgres_config, input).await?;
+
+    println!(
+        "successfully ran {}; response: {}",
+        args.workflow,
+        String::from_utf8_lossy(&output)
+    );
+
+    Ok(())
+}
@@ -0,0 +1,225 @@
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use directories::ProjectDirs;
+use sha2::{Digest, Sha256};
+
+use crate::config::PluginConfig;
+
+/// On-disk store of module bytes and compiled modules, keyed by content hash
+///
+/// Module bytes live under `modules/` and are evicted least recently used first once they
+/// exceed `max_bytes`. Compiled modules live under `compiled/` and are managed by the wasmtime
+/// cache, which applies the same limit.
+#[derive(Clone, Debug)]
+pub struct ModuleCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl ModuleCache {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_bytes,
+        }
+    }
+
+    /// Cache configured by `cache_dir`, or under the deadlift project cache directory
+    pub fn from_plugin_config(plugin_config: &PluginConfig) -> Option<Self> {
+        let dir = plugin_config.cache_dir.clone().or_else(|| {
+            ProjectDirs::from("com", "ZeroSync", "deadlift")
+                .map(|proj_dirs| proj_dirs.cache_dir().to_path_buf())
+        })?;
+
+        Some(Self::new(dir, plugin_config.cache_max_bytes))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the cached bytes for `sha256`, ignoring entries whose contents no longer match
+    pub async fn get(&self, sha256: &str) -> Option<Vec<u8>> {
+        let entry_path = self.entry_path(sha256);
+        let bytes = tokio::fs::read(&entry_path).await.ok()?;
+
+        if sha256_hex(&bytes) != sha256.to_lowercase() {
+            return None;
+        }
+
+        // mark as recently used for eviction
+        let _ = tokio::task::spawn_blocking(move || {
+            std::fs::File::options()
+                .write(true)
+                .open(&entry_path)
+                .and_then(|file| file.set_modified(SystemTime::now()))
+        })
+        .await;
+
+        Some(bytes)
+    }
+
+    /// Stores `bytes`, evicting old entries if over the size limit, and returns their sha256 hash
+    pub async fn put(&self, bytes: &[u8]) -> Result<String> {
+        let sha256 = sha256_hex(bytes);
+
+        let modules_dir = self.modules_dir();
+        tokio::fs::create_dir_all(&modules_dir).await?;
+
+        // write then rename so concurrent readers never observe a partial entry
+        let entry_path = self.entry_path(&sha256);
+        let tmp_path = entry_path.with_extension(format!("{}.tmp", std::process::id()));
+        tokio::fs::write(&tmp_path, bytes).await?;
+        tokio::fs::rename(&tmp_path, &entry_path).await?;
+
+        self.evict().await?;
+
+        Ok(sha256)
+    }
+
+    /// Writes a wasmtime cache config that stores compiled modules in this cache and returns its path
+    pub fn compilation_config(&self) -> Result<PathBuf> {
+        let compiled_dir = self.dir.join("compiled");
+        std::fs::create_dir_all(&compiled_dir)?;
+
+        let mut cache = toml::Table::new();
+        cache.insert(String::from("enabled"), true.into());
+        cache.insert(
+            String::from("directory"),
+            compiled_dir.to_string_lossy().into_owned().into(),
+        );
+        if self.max_bytes > 0 {
+            cache.insert(
+                String::from("files-total-size-soft-limit"),
+                self.max_bytes.to_string().into(),
+            );
+        }
+
+        let config = toml::to_string(&toml::Table::from_iter([(
+            String::from("cache"),
+            toml::Value::Table(cache),
+        )]))?;
+
+        let config_path = self.dir.join("wasmtime-cache.toml");
+        std::fs::write(&config_path, config)?;
+
+        Ok(config_path)
+    }
+
+    async fn evict(&self) -> Result<()> {
+        if self.max_bytes == 0 {
+            return Ok(());
+        }
+
+        let mut entries = vec![];
+        let mut total_bytes = 0;
+
+        let mut read_dir = tokio::fs::read_dir(self.modules_dir()).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() {
+                continue;
+            }
+
+            total_bytes += metadata.len();
+            entries.push((
+                metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                metadata.len(),
+                entry.path(),
+            ));
+        }
+
+        entries.sort_by_key(|(modified, _, _)| *modified);
+
+        for (_, len, path) in entries {
+            if total_bytes <= self.max_bytes {
+                break;
+            }
+
+            if tokio::fs::remove_file(&path).await.is_ok() {
+                total_bytes -= len;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn modules_dir(&self) -> PathBuf {
+        self.dir.join("modules")
+    }
+
+    fn entry_path(&self, sha256: &str) -> PathBuf {
+        self.modules_dir()
+            .join(format!("{}.wasm", sha256.to_lowercase()))
+    }
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn test_cache(max_bytes: u64) -> ModuleCache {
+        let dir = std::env::temp_dir().join(format!("deadlift-cache-é-{}", uuid::Uuid::new_v4()));
+        ModuleCache::new(dir, max_bytes)
+    }
+
+    fn set_modified(cache: &ModuleCache, sha256: &str, ago: Duration) {
+        std::fs::File::options()
+            .write(true)
+            .open(cache.entry_path(sha256))
+            .and_then(|file| file.set_modified(SystemTime::now() - ago))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        let cache = test_cache(8);
+
+        let a = cache.put(b"aaaa").await.unwrap();
+        let b = cache.put(b"bbbb").await.unwrap();
+        set_modified(&cache, &a, Duration::from_secs(20));
+        set_modified(&cache, &b, Duration::from_secs(10));
+
+        // reading a makes b the least recently used entry
+        assert_eq!(cache.get(&a).await.unwrap(), b"aaaa");
+        let c = cache.put(b"cccc").await.unwrap();
+
+        assert!(cache.get(&a).await.is_some());
+        assert!(cache.get(&b).await.is_none());
+        assert!(cache.get(&c).await.is_some());
+
+        let _ = std::fs::remove_dir_all(cache.dir());
+    }
+
+    #[test]
+    fn test_compilation_config() {
+        let cache = test_cache(1024);
+
+        let config_path = cache.compilation_config().unwrap();
+        let config = std::fs::read_to_string(config_path)
+            .unwrap()
+            .parse::<toml::Table>()
+            .unwrap();
+
+        assert_eq!(
+            config["cache"]["directory"].as_str(),
+            Some(cache.dir().join("compiled").to_string_lossy().as_ref())
+        );
+        assert_eq!(
+            config["cache"]["files-total-size-soft-limit"].as_str(),
+            Some("1024")
+        );
+
+        let _ = std::fs::remove_dir_all(cache.dir());
+    }
+}
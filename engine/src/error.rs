@@ -0,0 +1,87 @@
+use std::fmt;
+
+use crate::executor::MAX_EXECUTION_ID_LEN;
+
+/// Errors surfaced to callers of a workflow execution, identified by a stable code
+#[derive(Debug)]
+pub enum ExecutionError {
+    /// A payload did not match its declared JSON schema
+    SchemaViolation {
+        /// Stage whose payload failed validation; `None` for the workflow input
+        stage: Option<String>,
+        payload: PayloadKind,
+        /// JSON pointer to the failing value within the payload
+        pointer: String,
+        message: String,
+    },
+
+    /// A caller supplied execution id is not a valid subject and object name token
+    InvalidExecutionId { id: String },
+
+    /// A request asked for a different version of the workflow than the agent runs
+    VersionMismatch {
+        workflow: String,
+        running: u32,
+        requested: u32,
+    },
+}
+
+impl ExecutionError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ExecutionError::SchemaViolation { .. } => "schema_violation",
+            ExecutionError::InvalidExecutionId { .. } => "invalid_execution_id",
+            ExecutionError::VersionMismatch { .. } => "version_mismatch",
+        }
+    }
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutionError::SchemaViolation {
+                stage,
+                payload,
+                pointer,
+                message,
+            } => {
+                match stage {
+                    Some(stage) => write!(f, "{}: stage '{stage}' {payload}", self.code())?,
+                    None => write!(f, "{}: workflow {payload}", self.code())?,
+                }
+                write!(f, " at '{pointer}'; {message}")
+            }
+            ExecutionError::InvalidExecutionId { id } => write!(
+                f,
+                "{}: '{id}' must be 1 to {MAX_EXECUTION_ID_LEN} ASCII letters, digits, '-' or '_'",
+                self.code()
+            ),
+            ExecutionError::VersionMismatch {
+                workflow,
+                running,
+                requested,
+            } => write!(
+                f,
+                "{}: agent runs version {running} of workflow '{workflow}', not version {requested}",
+                self.code()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExecutionError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadKind {
+    Input,
+    Output,
+}
+
+impl fmt::Display for PayloadKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadKind::Input => write!(f, "input"),
+            PayloadKind::Output => write!(f, "output"),
+        }
+    }
+}
@@ -0,0 +1,685 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use petgraph::{graph::NodeIndex, visit::Dfs, Direction};
+use tracing::{info_span, level_filters::LevelFilter, Span};
+
+use crate::{
+    config::{WorkflowConfig, WorkflowStage},
+    error::{ExecutionError, PayloadKind},
+    history::{ExecutionRecord, ExecutionStatus, HistoryStore, StageRecord},
+    host::with_current_execution,
+    mapping::CompiledMapping,
+    metrics::METRICS,
+    schema::CompiledSchema,
+    telemetry::set_remote_parent,
+    utils::to_name_token,
+};
+
+const POOL_CHECKOUT_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub const MAX_EXECUTION_ID_LEN: usize = 128;
+
+pub fn new_execution_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Execution ids end up in history keys and object names, so caller supplied ones are limited to
+/// ASCII letters, digits, `-` and `_`
+pub fn validate_execution_id(id: &str) -> Result<(), ExecutionError> {
+    let valid = !id.is_empty()
+        && id.len() <= MAX_EXECUTION_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+
+    if !valid {
+        return Err(ExecutionError::InvalidExecutionId { id: id.to_string() });
+    }
+
+    Ok(())
+}
+
+/// Identifies a single execution and what started it
+#[derive(Clone, Debug)]
+pub struct ExecutionContext {
+    pub id: String,
+    pub trigger: String,
+
+    /// Id of the execution this one replays
+    pub replay_of: Option<String>,
+
+    /// W3C trace context of the caller, passed through to plugins
+    pub traceparent: Option<String>,
+}
+
+impl ExecutionContext {
+    pub fn new(trigger: impl Into<String>) -> Self {
+        Self {
+            id: new_execution_id(),
+            trigger: trigger.into(),
+            replay_of: None,
+            traceparent: None,
+        }
+    }
+
+    pub fn replay(replay_of: &str) -> Self {
+        Self {
+            replay_of: Some(replay_of.to_string()),
+            ..Self::new("replay")
+        }
+    }
+}
+
+/// Runs workflow stages in topological order
+///
+/// Root stages receive the workflow input, every other stage receives the output of its
+/// upstream stage, optionally transformed by the stage mapping. The output of the final stage,
+/// or of `output_stage` when stages fan out, is the workflow output.
+pub struct Executor {
+    workflow: WorkflowConfig,
+    pool: extism::Pool,
+    order: Vec<NodeIndex>,
+    output: NodeIndex,
+    input_schema: Option<CompiledSchema>,
+    stages: HashMap<NodeIndex, CompiledStage>,
+    history: Option<HistoryStore>,
+}
+
+/// Collects stage records of a single execution
+struct Recorder<'a> {
+    history: &'a HistoryStore,
+    execution_id: &'a str,
+    stages: Vec<StageRecord>,
+}
+
+struct CompiledStage {
+    log_level: Option<LevelFilter>,
+    mapping: Option<CompiledMapping>,
+    input_schema: Option<CompiledSchema>,
+    output_schema: Option<CompiledSchema>,
+}
+
+impl Executor {
+    pub fn new(workflow: WorkflowConfig, pool: extism::Pool) -> Result<Self> {
+        let order = petgraph::algo::toposort(&workflow.graph, None)
+            .map_err(|_| anyhow!("workflow '{}' contains a cycle", workflow.name))?;
+
+        if order.is_empty() {
+            return Err(anyhow!("workflow '{}' has no stages", workflow.name));
+        }
+
+        let input_schema = CompiledSchema::compile_opt(workflow.input_schema.as_ref())
+            .map_err(|e| anyhow!("workflow '{}' input_schema; {e}", workflow.name))?;
+
+        let mut stages = HashMap::new();
+        let mut stage_ids = std::collections::HashSet::new();
+        for &idx in &order {
+            let stage = &workflow.graph[idx];
+
+            let compiled_stage = CompiledStage {
+                log_level: stage
+                    .log_level
+                    .as_deref()
+                    .map(LevelFilter::from_str)
+                    .transpose()
+                    .map_err(|e| anyhow!("stage '{}' log_level; {e}", stage.id()))?,
+                mapping: stage
+                    .mapping
+                    .as_ref()
+                    .map(CompiledMapping::compile)
+                    .transpose()
+                    .map_err(|e| anyhow!("stage '{}' mapping; {e}", stage.id()))?,
+                input_schema: CompiledSchema::compile_opt(stage.input_schema.as_ref())
+                    .map_err(|e| anyhow!("stage '{}' input_schema; {e}", stage.id()))?,
+                output_schema: CompiledSchema::compile_opt(stage.output_schema.as_ref())
+                    .map_err(|e| anyhow!("stage '{}' output_schema; {e}", stage.id()))?,
+            };
+            stages.insert(idx, compiled_stage);
+
+            if !stage_ids.insert(stage.id()) {
+                return Err(anyhow!("duplicate stage name '{}'", stage.id()));
+            }
+
+            if workflow
+                .graph
+                .neighbors_directed(idx, Direction::Incoming)
+                .count()
+                > 1
+            {
+                return Err(anyhow!(
+                    "stage '{}' has more than one upstream stage",
+                    stage.id()
+                ));
+            }
+        }
+
+        let output = match &workflow.output_stage {
+            Some(stage_id) => order
+                .iter()
+                .copied()
+                .find(|&idx| workflow.graph[idx].id() == stage_id)
+                .ok_or_else(|| anyhow!("output_stage '{stage_id}' not found"))?,
+            None => {
+                let final_stages = order
+                    .iter()
+                    .copied()
+                    .filter(|&idx| {
+                        workflow
+                            .graph
+                            .neighbors_directed(idx, Direction::Outgoing)
+                            .next()
+                            .is_none()
+                    })
+                    .collect::<Vec<_>>();
+
+                match final_stages.as_slice() {
+                    [idx] => *idx,
+                    _ => {
+                        return Err(anyhow!(
+                            "workflow '{}' has more than one final stage ({}); set output_stage",
+                            workflow.name,
+                            final_stages
+                                .iter()
+                                .map(|&idx| workflow.graph[idx].id())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ))
+                    }
+                }
+            }
+        };
+
+        Ok(Self {
+            workflow,
+            pool,
+            order,
+            output,
+            input_schema,
+            stages,
+            history: None,
+        })
+    }
+
+    /// Records every execution in the given history store
+    pub fn with_history(mut self, history: HistoryStore) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    pub fn workflow(&self) -> &WorkflowConfig {
+        &self.workflow
+    }
+
+    /// Executes the workflow on the blocking thread pool
+    pub async fn execute_async(
+        self: &Arc<Self>,
+        context: ExecutionContext,
+        input: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let executor = self.clone();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || span.in_scope(|| executor.execute(&context, input)))
+            .await?
+    }
+
+    /// Re-runs the execution `context.replay_of` from its retained inputs, either from the
+    /// start or from `from_stage` onwards
+    pub async fn replay(
+        self: &Arc<Self>,
+        context: ExecutionContext,
+        from_stage: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        let replay_of = context
+            .replay_of
+            .as_deref()
+            .ok_or_else(|| anyhow!("execution to replay is not set"))?;
+        validate_execution_id(replay_of)?;
+
+        let history = self
+            .history
+            .as_ref()
+            .ok_or_else(|| anyhow!("execution history is disabled on this agent"))?;
+
+        self.check_replayable(&history.get(replay_of).await?)?;
+        let input = history.load_input(replay_of, None).await?;
+
+        let start = match from_stage {
+            Some(stage_id) => {
+                let idx = self.stage_index(stage_id)?;
+                let stage_id = self.workflow.graph[idx].id();
+
+                Some((idx, history.load_input(replay_of, Some(stage_id)).await?))
+            }
+            None => None,
+        };
+
+        let executor = self.clone();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            span.in_scope(|| executor.execute_from(&context, input, start))
+        })
+        .await?
+    }
+
+    /// Only executions of this workflow can be replayed by it
+    fn check_replayable(&self, record: &ExecutionRecord) -> Result<()> {
+        if record.workflow != self.workflow.name {
+            return Err(anyhow!(
+                "execution '{}' is of workflow '{}', not '{}'",
+                record.id,
+                record.workflow,
+                self.workflow.name
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Runs a single stage on the blocking thread pool, with the input as is
+    ///
+    /// The stage is named by its id or its id as a subject token. Stage runs are not recorded in
+    /// the execution history.
+    pub async fn execute_stage_async(
+        self: &Arc<Self>,
+        context: ExecutionContext,
+        stage: &str,
+        input: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        validate_execution_id(&context.id)?;
+        let idx = self.stage_index(stage)?;
+
+        let executor = self.clone();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            span.in_scope(|| executor.execute_stage(&context, idx, input))
+        })
+        .await?
+    }
+
+    fn stage_index(&self, stage: &str) -> Result<NodeIndex> {
+        self.order
+            .iter()
+            .copied()
+            .find(|&idx| {
+                let id = self.workflow.graph[idx].id();
+                id == stage || to_name_token(id) == stage
+            })
+            .ok_or_else(|| anyhow!("stage '{stage}' not found"))
+    }
+
+    fn execute_stage(
+        &self,
+        context: &ExecutionContext,
+        idx: NodeIndex,
+        input: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let stage = &self.workflow.graph[idx];
+
+        let span = info_span!(
+            "stage_execution",
+            workflow = %self.workflow.name,
+            stage = %stage.id(),
+            execution_id = %context.id,
+            trigger = %context.trigger,
+        );
+        if Span::current().is_none() {
+            set_remote_parent(&span, context.traceparent.as_deref());
+        }
+        let _entered = span.enter();
+
+        self.run_stage(context, stage, &self.stages[&idx], input)
+    }
+
+    /// Executes every stage of the workflow; blocks on plugin calls
+    pub fn execute(&self, context: &ExecutionContext, input: Vec<u8>) -> Result<Vec<u8>> {
+        self.execute_from(context, input, None)
+    }
+
+    /// Executes the workflow, or only `start` and its downstream stages when given; the start
+    /// stage receives the given input as is, without its mapping
+    fn execute_from(
+        &self,
+        context: &ExecutionContext,
+        input: Vec<u8>,
+        start: Option<(NodeIndex, Vec<u8>)>,
+    ) -> Result<Vec<u8>> {
+        validate_execution_id(&context.id)?;
+
+        let span = info_span!(
+            "execution",
+            workflow = %self.workflow.name,
+            execution_id = %context.id,
+            trigger = %context.trigger,
+        );
+        // triggers without a receive span continue the caller's trace here
+        if Span::current().is_none() {
+            set_remote_parent(&span, context.traceparent.as_deref());
+        }
+        let _entered = span.enter();
+
+        let _in_flight = METRICS.execution_started(&self.workflow.name);
+        let started = Instant::now();
+
+        let Some(history) = &self.history else {
+            let res = self.execute_stages(context, &input, start, None);
+            METRICS.execution_finished(&self.workflow.name, res.is_ok(), started.elapsed());
+            return res;
+        };
+
+        let mut record = ExecutionRecord {
+            id: context.id.clone(),
+            workflow: self.workflow.name.clone(),
+            workflow_version: self.workflow.version,
+            trigger: context.trigger.clone(),
+            replay_of: context.replay_of.clone(),
+            status: ExecutionStatus::Running,
+            started_at: Utc::now(),
+            duration_ms: None,
+            input: history.payload(&input),
+            output: None,
+            error: None,
+            stages: vec![],
+        };
+        history.record(&record);
+        history.retain_input(&context.id, None, &input);
+
+        let mut recorder = Recorder {
+            history,
+            execution_id: &context.id,
+            stages: vec![],
+        };
+        let res = self.execute_stages(context, &input, start, Some(&mut recorder));
+
+        record.stages = recorder.stages;
+        record.duration_ms = Some(started.elapsed().as_millis() as u64);
+        match &res {
+            Ok(output) => {
+                record.status = ExecutionStatus::Succeeded;
+                record.output = Some(history.payload(output));
+            }
+            Err(e) => {
+                record.status = ExecutionStatus::Failed;
+                record.error = Some(e.to_string());
+            }
+        }
+        history.record(&record);
+
+        METRICS.execution_finished(&self.workflow.name, res.is_ok(), started.elapsed());
+
+        res
+    }
+
+    fn execute_stages(
+        &self,
+        context: &ExecutionContext,
+        input: &[u8],
+        start: Option<(NodeIndex, Vec<u8>)>,
+        mut recorder: Option<&mut Recorder>,
+    ) -> Result<Vec<u8>> {
+        if let Some(schema) = &self.input_schema {
+            schema.validate(None, PayloadKind::Input, input)?;
+        }
+
+        let (start_idx, mut start_input) = start.unzip();
+        let downstream = start_idx.map(|start_idx| {
+            let mut dfs = Dfs::new(&self.workflow.graph, start_idx);
+            let mut downstream = HashSet::new();
+            while let Some(idx) = dfs.next(&self.workflow.graph) {
+                downstream.insert(idx);
+            }
+            downstream
+        });
+
+        let mut outputs: HashMap<NodeIndex, Vec<u8>> = HashMap::new();
+        let mut last_idx = None;
+
+        for &idx in &self.order {
+            if downstream
+                .as_ref()
+                .is_some_and(|downstream| !downstream.contains(&idx))
+            {
+                continue;
+            }
+
+            let stage = &self.workflow.graph[idx];
+            let compiled_stage = &self.stages[&idx];
+
+            let stage_input = if Some(idx) == start_idx {
+                start_input.take().unwrap_or_default()
+            } else {
+                let stage_input = match self
+                    .workflow
+                    .graph
+                    .neighbors_directed(idx, Direction::Incoming)
+                    .next()
+                {
+                    Some(upstream_idx) => outputs[&upstream_idx].clone(),
+                    None => input.to_vec(),
+                };
+
+                match &compiled_stage.mapping {
+                    Some(mapping) => mapping
+                        .apply(&stage_input, input)
+                        .map_err(|e| anyhow!("stage '{}' mapping failed; {e}", stage.id()))?,
+                    None => stage_input,
+                }
+            };
+
+            let output = match recorder.as_deref_mut() {
+                Some(recorder) => {
+                    let started_at = Utc::now();
+                    let started = Instant::now();
+                    let stage_input_record = recorder.history.payload(&stage_input);
+                    recorder.history.retain_input(
+                        recorder.execution_id,
+                        Some(stage.id()),
+                        &stage_input,
+                    );
+
+                    let res = self.run_stage(context, stage, compiled_stage, stage_input);
+
+                    recorder.stages.push(StageRecord {
+                        stage: stage.id().to_string(),
+                        status: if res.is_ok() {
+                            ExecutionStatus::Succeeded
+                        } else {
+                            ExecutionStatus::Failed
+                        },
+                        started_at,
+                        duration_ms: Some(started.elapsed().as_millis() as u64),
+                        input: stage_input_record,
+                        output: res
+                            .as_ref()
+                            .ok()
+                            .map(|output| recorder.history.payload(output)),
+                        error: res.as_ref().err().map(|e| e.to_string()),
+                    });
+
+                    res?
+                }
+                None => self.run_stage(context, stage, compiled_stage, stage_input)?,
+            };
+
+            outputs.insert(idx, output);
+            last_idx = Some(idx);
+        }
+
+        // a replay from a stage on another branch never reaches the output stage
+        outputs
+            .remove(&self.output)
+            .or_else(|| last_idx.and_then(|last_idx| outputs.remove(&last_idx)))
+            .ok_or_else(|| anyhow!("failed to resolve workflow output"))
+    }
+
+    fn run_stage(
+        &self,
+        context: &ExecutionContext,
+        stage: &WorkflowStage,
+        compiled_stage: &CompiledStage,
+        input: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        if let Some(schema) = &compiled_stage.input_schema {
+            schema.validate(Some(stage.id()), PayloadKind::Input, &input)?;
+        }
+
+        let output = self.call_stage(context, stage, compiled_stage.log_level, input)?;
+
+        if let Some(schema) = &compiled_stage.output_schema {
+            schema.validate(Some(stage.id()), PayloadKind::Output, &output)?;
+        }
+
+        Ok(output)
+    }
+
+    fn call_stage(
+        &self,
+        context: &ExecutionContext,
+        stage: &WorkflowStage,
+        log_level: Option<LevelFilter>,
+        input: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let key = stage.id().to_string();
+
+        let checkout_started = Instant::now();
+        let pool_plugin = info_span!("pool_checkout", stage = %key)
+            .in_scope(|| self.pool.get(&key, POOL_CHECKOUT_TIMEOUT));
+        METRICS.pool_checked_out(
+            &self.workflow.name,
+            &key,
+            matches!(pool_plugin, Ok(None)),
+            checkout_started.elapsed(),
+        );
+
+        let pool_plugin = pool_plugin
+            .map_err(|e| anyhow!("failed to resolve plugin for stage '{key}'; {e}"))?
+            .ok_or_else(|| anyhow!("failed to resolve plugin for stage '{key}'; timed out"))?;
+
+        let span = info_span!(
+            "plugin_call",
+            stage = %key,
+            function = %stage.plugin_function_name,
+        );
+
+        // the plugin runs on this thread, so host functions can look up the current execution
+        let call_started = Instant::now();
+        let output = span.in_scope(|| {
+            with_current_execution(context, &self.workflow.name, &key, log_level, || {
+                pool_plugin.call::<Vec<u8>, Vec<u8>>(&stage.plugin_function_name, input)
+            })
+        });
+        METRICS.stage_called(
+            &self.workflow.name,
+            &key,
+            output.is_ok(),
+            call_started.elapsed(),
+        );
+
+        output.map_err(|e| anyhow!("stage '{key}' failed; {e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::PayloadRecord;
+
+    fn fan_out_workflow(output_stage: Option<&str>) -> WorkflowConfig {
+        let mut workflow = serde_yaml::from_str::<WorkflowConfig>(
+            "
+            name: fan out
+            nodes:
+              - object_name: a
+                plugin_function_name: a
+              - object_name: b
+                plugin_function_name: b
+              - object_name: c
+                plugin_function_name: c
+            node_holes: []
+            edge_property: directed
+            edges:
+              - [0, 1, null]
+              - [0, 2, null]
+            ",
+        )
+        .unwrap();
+        workflow.output_stage = output_stage.map(String::from);
+
+        workflow
+    }
+
+    #[test]
+    fn test_output_stage() {
+        let err = Executor::new(fan_out_workflow(None), extism::Pool::new(1))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("more than one final stage"));
+
+        let executor = Executor::new(fan_out_workflow(Some("c")), extism::Pool::new(1)).unwrap();
+        assert_eq!(executor.workflow.graph[executor.output].id(), "c");
+
+        assert!(Executor::new(fan_out_workflow(Some("d")), extism::Pool::new(1)).is_err());
+    }
+
+    #[test]
+    fn test_validate_execution_id() {
+        assert!(validate_execution_id(&new_execution_id()).is_ok());
+        assert!(validate_execution_id("order_42-retry").is_ok());
+
+        for id in [
+            "",
+            "a.b",
+            "a/b",
+            "a b",
+            "a*",
+            &"a".repeat(MAX_EXECUTION_ID_LEN + 1),
+        ] {
+            assert!(matches!(
+                validate_execution_id(id),
+                Err(ExecutionError::InvalidExecutionId { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_replay_context() {
+        let context = ExecutionContext::replay("abc");
+
+        assert_eq!(context.trigger, "replay");
+        assert_eq!(context.replay_of.as_deref(), Some("abc"));
+        assert_ne!(context.id, "abc");
+    }
+
+    #[test]
+    fn test_check_replayable() {
+        let executor = Executor::new(fan_out_workflow(Some("c")), extism::Pool::new(1)).unwrap();
+
+        let mut record = ExecutionRecord {
+            id: "abc".to_string(),
+            workflow: "fan out".to_string(),
+            workflow_version: 1,
+            trigger: "nats".to_string(),
+            replay_of: None,
+            status: ExecutionStatus::Failed,
+            started_at: Utc::now(),
+            duration_ms: None,
+            input: PayloadRecord::new(b"1", 16),
+            output: None,
+            error: None,
+            stages: vec![],
+        };
+        assert!(executor.check_replayable(&record).is_ok());
+
+        record.workflow = "fan in".to_string();
+        let err = executor.check_replayable(&record).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("execution 'abc' is of workflow 'fan in', not 'fan out'"));
+    }
+}
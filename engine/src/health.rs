@@ -0,0 +1,236 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_nats::connection::State;
+use serde::Serialize;
+
+use crate::shutdown::Shutdown;
+
+/// Liveness and readiness of the engine, served at `/healthz` and `/readyz` and by the
+/// `health` service endpoint
+pub struct Health {
+    nats_connected: Box<dyn Fn() -> bool + Send + Sync>,
+    workflow_loaded: AtomicBool,
+    modules_loaded: AtomicBool,
+    execution_thread_enabled: bool,
+    /// Whether the execution thread of each workflow is running, with the id of its guard
+    execution_threads: Mutex<HashMap<String, (u64, bool)>>,
+    next_guard_id: AtomicU64,
+    draining: AtomicBool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthReport {
+    /// False once a thread the engine depends on has stopped; the agent should be restarted
+    pub live: bool,
+
+    /// Whether the agent can serve executions; false while shutting down
+    pub ready: bool,
+    pub nats_connected: bool,
+    pub workflow_loaded: bool,
+    pub modules_loaded: bool,
+
+    /// Whether the execution threads of all workflows are running; unset when disabled
+    pub execution_thread_running: Option<bool>,
+    pub draining: bool,
+}
+
+impl Health {
+    pub fn new(nc: async_nats::Client, execution_thread_enabled: bool) -> Self {
+        Self::with_nats_state(
+            move || nc.connection_state() == State::Connected,
+            execution_thread_enabled,
+        )
+    }
+
+    /// Health with the NATS connection state given by `nats_connected`
+    pub(crate) fn with_nats_state(
+        nats_connected: impl Fn() -> bool + Send + Sync + 'static,
+        execution_thread_enabled: bool,
+    ) -> Self {
+        Self {
+            nats_connected: Box::new(nats_connected),
+            workflow_loaded: AtomicBool::new(false),
+            modules_loaded: AtomicBool::new(false),
+            execution_thread_enabled,
+            execution_threads: Mutex::new(HashMap::new()),
+            next_guard_id: AtomicU64::new(0),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_workflow_loaded(&self) {
+        self.workflow_loaded.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_modules_loaded(&self) {
+        self.modules_loaded.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Marks the execution thread of a workflow as running until the guard is dropped, including
+    /// when the thread panics or is aborted
+    ///
+    /// A thread that stops once its workflow is draining is forgotten rather than marked as
+    /// stopped, so unloading a workflow keeps the agent live.
+    pub(crate) fn execution_thread_guard(
+        self: &Arc<Self>,
+        workflow: &str,
+        shutdown: Shutdown,
+    ) -> RunningGuard {
+        let id = self.next_guard_id.fetch_add(1, Ordering::Relaxed);
+
+        self.execution_threads
+            .lock()
+            .unwrap()
+            .insert(workflow.to_string(), (id, true));
+
+        RunningGuard {
+            health: self.clone(),
+            workflow: workflow.to_string(),
+            id,
+            shutdown,
+        }
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let nats_connected = (self.nats_connected)();
+        let workflow_loaded = self.workflow_loaded.load(Ordering::Relaxed);
+        let modules_loaded = self.modules_loaded.load(Ordering::Relaxed);
+        let execution_thread_running = self.execution_thread_enabled.then(|| {
+            self.execution_threads
+                .lock()
+                .unwrap()
+                .values()
+                .all(|(_, running)| *running)
+        });
+        let draining = self.draining.load(Ordering::Relaxed);
+
+        // the execution thread only starts once modules are loaded
+        let live = !modules_loaded || execution_thread_running != Some(false);
+
+        HealthReport {
+            live,
+            ready: live && !draining && nats_connected && workflow_loaded && modules_loaded,
+            nats_connected,
+            workflow_loaded,
+            modules_loaded,
+            execution_thread_running,
+            draining,
+        }
+    }
+}
+
+pub(crate) struct RunningGuard {
+    health: Arc<Health>,
+    workflow: String,
+    id: u64,
+    shutdown: Shutdown,
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        let mut threads = self.health.execution_threads.lock().unwrap();
+
+        // a reloaded workflow's new thread has its own guard
+        if threads.get(&self.workflow).map(|(id, _)| *id) != Some(self.id) {
+            return;
+        }
+
+        if self.shutdown.is_draining() {
+            threads.remove(&self.workflow);
+        } else {
+            threads.insert(self.workflow.clone(), (self.id, false));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown::shutdown_channel;
+
+    fn loaded_health(nats_connected: Arc<AtomicBool>) -> Arc<Health> {
+        let health = Arc::new(Health::with_nats_state(
+            move || nats_connected.load(Ordering::Relaxed),
+            true,
+        ));
+        health.set_workflow_loaded();
+        health.set_modules_loaded();
+
+        health
+    }
+
+    #[test]
+    fn test_report_readiness() {
+        let nats_connected = Arc::new(AtomicBool::new(true));
+        let health = Health::with_nats_state(|| true, true);
+
+        // live while starting, ready once loaded
+        let report = health.report();
+        assert!(report.live && !report.ready);
+        assert_eq!(report.execution_thread_running, Some(true));
+
+        let health = loaded_health(nats_connected.clone());
+        let (_controller, shutdown) = shutdown_channel();
+        let _running = health.execution_thread_guard("math", shutdown);
+        assert!(health.report().ready);
+
+        nats_connected.store(false, Ordering::Relaxed);
+        let report = health.report();
+        assert!(report.live && !report.ready && !report.nats_connected);
+
+        nats_connected.store(true, Ordering::Relaxed);
+        health.set_draining();
+        let report = health.report();
+        assert!(report.live && !report.ready && report.draining);
+
+        let health = Health::with_nats_state(|| true, false);
+        health.set_workflow_loaded();
+        health.set_modules_loaded();
+        let report = health.report();
+        assert!(report.ready);
+        assert_eq!(report.execution_thread_running, None);
+    }
+
+    #[test]
+    fn test_report_execution_threads() {
+        let health = loaded_health(Arc::new(AtomicBool::new(true)));
+
+        // a thread stopping outside of shutdown is fatal
+        let (_controller, shutdown) = shutdown_channel();
+        drop(health.execution_thread_guard("math", shutdown));
+        let report = health.report();
+        assert!(!report.live && !report.ready);
+        assert_eq!(report.execution_thread_running, Some(false));
+
+        // a restarted thread replaces the stopped one
+        let (_controller, shutdown) = shutdown_channel();
+        let running = health.execution_thread_guard("math", shutdown.clone());
+        assert!(health.report().live);
+
+        // the previous thread of a reloaded workflow stopping late doesn't affect the new one
+        let reloaded = health.execution_thread_guard("math", shutdown);
+        drop(running);
+        assert!(health.report().ready);
+
+        // an unloaded workflow's thread is forgotten
+        let (controller, shutdown) = shutdown_channel();
+        let unloaded = health.execution_thread_guard("stats", shutdown);
+        controller.drain();
+        drop(unloaded);
+        assert!(health.report().ready);
+        assert_eq!(health.execution_threads.lock().unwrap().len(), 1);
+
+        drop(reloaded);
+        assert!(!health.report().live);
+    }
+}
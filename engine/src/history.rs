@@ -0,0 +1,460 @@
+use std::{future::Future, ops::ControlFlow, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_nats::jetstream::{
+    consumer::{pull, DeliverPolicy},
+    kv, object_store,
+};
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncReadExt, sync::mpsc, task::JoinHandle};
+use tracing::error;
+
+use crate::{
+    config::HistoryConfig,
+    executor::validate_execution_id,
+    utils::{get_or_create_key_value, get_or_create_object_store_with_config, to_name_token},
+};
+
+pub const EXECUTION_BUCKET_NAME: &str = "deadlift_executions";
+pub const EXECUTION_INPUT_BUCKET_NAME: &str = "deadlift_execution_inputs";
+
+/// Record writes waiting for the writer before new ones are dropped
+const MAX_PENDING_WRITES: usize = 1024;
+
+/// Persisted summary of a workflow execution
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExecutionRecord {
+    pub id: String,
+    pub workflow: String,
+    pub workflow_version: u32,
+
+    /// What started the execution, e.g. `nats`, `schedule:nightly` or `webhook`
+    pub trigger: String,
+
+    /// Id of the execution this one replays
+    #[serde(default)]
+    pub replay_of: Option<String>,
+    pub status: ExecutionStatus,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: Option<u64>,
+    pub input: PayloadRecord,
+    pub output: Option<PayloadRecord>,
+    pub error: Option<String>,
+    pub stages: Vec<StageRecord>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StageRecord {
+    pub stage: String,
+    pub status: ExecutionStatus,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: Option<u64>,
+    pub input: PayloadRecord,
+    pub output: Option<PayloadRecord>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutionStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl std::fmt::Display for ExecutionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Running => write!(f, "running"),
+            Self::Succeeded => write!(f, "succeeded"),
+            Self::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// Size, hash and truncated preview of a payload; full payloads are not kept
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PayloadRecord {
+    pub size: usize,
+    pub sha256: String,
+    pub preview: String,
+}
+
+impl PayloadRecord {
+    pub fn new(payload: &[u8], max_preview_bytes: usize) -> Self {
+        let preview = &payload[..payload.len().min(max_preview_bytes)];
+
+        Self {
+            size: payload.len(),
+            sha256: crate::cache::sha256_hex(payload),
+            preview: String::from_utf8_lossy(preview).into_owned(),
+        }
+    }
+}
+
+/// Execution records in a key value bucket, keyed by `<workflow>.<execution id>`
+///
+/// Records are written by a single task per store, in the order they are recorded, so the final
+/// record of an execution always replaces its running record
+#[derive(Clone)]
+pub struct HistoryStore {
+    kv: kv::Store,
+    writer: RecordWriter,
+    inputs: Option<object_store::ObjectStore>,
+    runtime: tokio::runtime::Handle,
+    max_preview_bytes: usize,
+}
+
+impl HistoryStore {
+    /// Opens the execution bucket, creating it with the configured retention; must be called
+    /// from within the tokio runtime
+    pub async fn open(js: &async_nats::jetstream::Context, config: &HistoryConfig) -> Result<Self> {
+        let kv = get_or_create_key_value(
+            js,
+            kv::Config {
+                bucket: EXECUTION_BUCKET_NAME.to_string(),
+                history: 1,
+                max_age: Duration::from_secs(config.max_age_secs),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        let inputs = if config.retain_inputs {
+            Some(
+                get_or_create_object_store_with_config(
+                    js,
+                    object_store::Config {
+                        bucket: EXECUTION_INPUT_BUCKET_NAME.to_string(),
+                        max_age: Duration::from_secs(config.inputs_max_age_secs),
+                        ..Default::default()
+                    },
+                )
+                .await?,
+            )
+        } else {
+            None
+        };
+
+        let runtime = tokio::runtime::Handle::current();
+        let (writer, _) = RecordWriter::spawn(&runtime, {
+            let kv = kv.clone();
+            move |key, value| {
+                let kv = kv.clone();
+                async move {
+                    kv.put(&key, value.into()).await?;
+                    Ok(())
+                }
+            }
+        });
+
+        Ok(Self {
+            kv,
+            writer,
+            inputs,
+            runtime,
+            max_preview_bytes: config.max_preview_bytes,
+        })
+    }
+
+    pub fn payload(&self, payload: &[u8]) -> PayloadRecord {
+        PayloadRecord::new(payload, self.max_preview_bytes)
+    }
+
+    /// Writes the record in the background; history is best effort and never fails an execution
+    pub fn record(&self, record: &ExecutionRecord) {
+        self.writer.write(record);
+    }
+
+    /// Keeps the full workflow input, or stage input, for replays; written in the background
+    pub fn retain_input(&self, execution_id: &str, stage: Option<&str>, input: &[u8]) {
+        let Some(inputs) = self.inputs.clone() else {
+            return;
+        };
+
+        let name = input_object_name(execution_id, stage);
+        let input = input.to_vec();
+
+        self.runtime.spawn(async move {
+            if let Err(e) = inputs.put(name.as_str(), &mut input.as_slice()).await {
+                error!("failed to retain execution input {name}; {e}");
+            }
+        });
+    }
+
+    /// Finds a record by execution id, regardless of workflow
+    pub async fn get(&self, id: &str) -> Result<ExecutionRecord> {
+        find_record(&self.kv, id).await
+    }
+
+    /// Loads a retained workflow input, or stage input
+    pub async fn load_input(&self, execution_id: &str, stage: Option<&str>) -> Result<Vec<u8>> {
+        let inputs = self
+            .inputs
+            .as_ref()
+            .ok_or_else(|| anyhow!("execution inputs are not retained on this agent"))?;
+
+        let name = input_object_name(execution_id, stage);
+        let mut object = inputs
+            .get(&name)
+            .await
+            .map_err(|e| anyhow!("input {name} is not retained; {e}"))?;
+
+        let mut input = vec![];
+        object.read_to_end(&mut input).await?;
+
+        Ok(input)
+    }
+}
+
+/// Read side of the execution bucket, used by the cli
+pub struct HistoryReader {
+    kv: kv::Store,
+}
+
+impl HistoryReader {
+    pub async fn open(js: &async_nats::jetstream::Context) -> Result<Self> {
+        let kv = js
+            .get_key_value(EXECUTION_BUCKET_NAME)
+            .await
+            .map_err(|e| anyhow!("failed to open execution history; {e}"))?;
+
+        Ok(Self { kv })
+    }
+
+    /// Lists records, newest first, optionally for a single workflow
+    ///
+    /// Records are read in the order they were last written, so every matching one is read to
+    /// find the newest, but only the newest `limit` are kept meanwhile.
+    pub async fn list(&self, workflow: Option<&str>, limit: usize) -> Result<Vec<ExecutionRecord>> {
+        let filter = workflow
+            .map(|workflow| format!("{}.>", to_name_token(workflow)))
+            .unwrap_or_else(|| String::from(">"));
+
+        let mut records = vec![];
+        read_records(&self.kv, &filter, |record| {
+            records.push(record);
+            if records.len() >= limit.max(1) * 2 {
+                newest(&mut records, limit);
+            }
+            ControlFlow::Continue(())
+        })
+        .await?;
+        newest(&mut records, limit);
+
+        Ok(records)
+    }
+
+    /// Finds a record by execution id, regardless of workflow
+    pub async fn get(&self, id: &str) -> Result<ExecutionRecord> {
+        find_record(&self.kv, id).await
+    }
+}
+
+async fn find_record(kv: &kv::Store, id: &str) -> Result<ExecutionRecord> {
+    // the id becomes a subject token of the filter
+    validate_execution_id(id)?;
+
+    let mut found = None;
+    read_records(kv, &format!("*.{id}"), |record| {
+        found = Some(record);
+        ControlFlow::Break(())
+    })
+    .await?;
+
+    found.ok_or_else(|| anyhow!("execution '{id}' not found"))
+}
+
+/// Passes the latest record of every key matching `filter`, e.g. `<workflow>.>`, to `f` until it
+/// breaks; records are read in one pass over the bucket's stream rather than a get per key
+async fn read_records(
+    kv: &kv::Store,
+    filter: &str,
+    mut f: impl FnMut(ExecutionRecord) -> ControlFlow<()>,
+) -> Result<()> {
+    let consumer = kv
+        .stream
+        .create_consumer(pull::OrderedConfig {
+            filter_subject: format!("{}{filter}", kv.prefix),
+            deliver_policy: DeliverPolicy::LastPerSubject,
+            ..Default::default()
+        })
+        .await?;
+
+    if consumer.cached_info().num_pending == 0 {
+        return Ok(());
+    }
+
+    let mut messages = consumer.messages().await?;
+    while let Some(msg) = messages.try_next().await? {
+        // deleted and purged keys leave empty markers
+        if !msg.payload.is_empty() {
+            let record = serde_json::from_slice::<ExecutionRecord>(&msg.payload)?;
+            if f(record).is_break() {
+                break;
+            }
+        }
+
+        // the consumer keeps waiting for new writes once it has caught up
+        if msg.info().map_or(true, |info| info.pending == 0) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Keeps the `limit` most recently started records, newest first
+fn newest(records: &mut Vec<ExecutionRecord>, limit: usize) {
+    records.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+    records.truncate(limit);
+}
+
+/// Queue of record writes, applied one at a time by a single task
+#[derive(Clone)]
+struct RecordWriter {
+    writes: mpsc::Sender<(String, Vec<u8>)>,
+}
+
+impl RecordWriter {
+    /// Spawns the task applying writes with `put`, which ends once every writer is dropped
+    fn spawn<F, Fut>(runtime: &tokio::runtime::Handle, put: F) -> (Self, JoinHandle<()>)
+    where
+        F: Fn(String, Vec<u8>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (writes, mut rx) = mpsc::channel::<(String, Vec<u8>)>(MAX_PENDING_WRITES);
+
+        let handle = runtime.spawn(async move {
+            while let Some((key, value)) = rx.recv().await {
+                if let Err(e) = put(key.clone(), value).await {
+                    error!("failed to write execution record {key}; {e}");
+                }
+            }
+        });
+
+        (Self { writes }, handle)
+    }
+
+    fn write(&self, record: &ExecutionRecord) {
+        let key = record_key(&record.workflow, &record.id);
+
+        let value = match serde_json::to_vec(record) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("failed to serialize execution record {}; {e}", record.id);
+                return;
+            }
+        };
+
+        if let Err(e) = self.writes.try_send((key, value)) {
+            error!("failed to write execution record {}; {e}", record.id);
+        }
+    }
+}
+
+fn input_object_name(execution_id: &str, stage: Option<&str>) -> String {
+    match stage {
+        Some(stage) => format!("{execution_id}/stages/{stage}"),
+        None => format!("{execution_id}/input"),
+    }
+}
+
+fn record_key(workflow: &str, id: &str) -> String {
+    format!("{}.{id}", to_name_token(workflow))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_record_truncates_preview() {
+        let record = PayloadRecord::new(b"hello world", 5);
+
+        assert_eq!(record.size, 11);
+        assert_eq!(record.preview, "hello");
+        assert_eq!(record.sha256, crate::cache::sha256_hex(b"hello world"));
+    }
+
+    #[test]
+    fn test_newest() {
+        let record = |id: &str, minutes_ago: i64| ExecutionRecord {
+            id: id.to_string(),
+            workflow: "math".to_string(),
+            workflow_version: 1,
+            trigger: "nats".to_string(),
+            replay_of: None,
+            status: ExecutionStatus::Succeeded,
+            started_at: Utc::now() - chrono::Duration::minutes(minutes_ago),
+            duration_ms: Some(1),
+            input: PayloadRecord::new(b"1", 16),
+            output: None,
+            error: None,
+            stages: vec![],
+        };
+
+        let mut records = vec![record("a", 3), record("b", 1), record("c", 2)];
+        newest(&mut records, 2);
+
+        assert_eq!(
+            records.iter().map(|r| r.id.as_str()).collect::<Vec<_>>(),
+            ["b", "c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_records_are_written_in_order() {
+        let written = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+
+        let (writer, handle) = RecordWriter::spawn(&tokio::runtime::Handle::current(), {
+            let written = written.clone();
+            move |key, value| {
+                let written = written.clone();
+                async move {
+                    // the running record is the slowest to write, so it would land last if
+                    // writes weren't ordered
+                    let record = serde_json::from_slice::<ExecutionRecord>(&value)?;
+                    if record.status == ExecutionStatus::Running {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                    }
+                    written.lock().unwrap().push((key, record.status));
+                    Ok(())
+                }
+            }
+        });
+
+        let mut record = ExecutionRecord {
+            id: "abc".to_string(),
+            workflow: "do some math".to_string(),
+            workflow_version: 1,
+            trigger: "nats".to_string(),
+            replay_of: None,
+            status: ExecutionStatus::Running,
+            started_at: Utc::now(),
+            duration_ms: None,
+            input: PayloadRecord::new(b"1", 16),
+            output: None,
+            error: None,
+            stages: vec![],
+        };
+        writer.write(&record);
+
+        record.status = ExecutionStatus::Succeeded;
+        record.output = Some(PayloadRecord::new(b"10", 16));
+        writer.write(&record);
+
+        drop(writer);
+        handle.await.unwrap();
+
+        assert_eq!(
+            *written.lock().unwrap(),
+            [
+                ("do_some_math.abc".to_string(), ExecutionStatus::Running),
+                ("do_some_math.abc".to_string(), ExecutionStatus::Succeeded),
+            ]
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::cell::RefCell;
+
+use extism::{CurrentPlugin, UserData, Val};
+use serde::Serialize;
+use tracing::{info_span, level_filters::LevelFilter};
+
+use crate::executor::ExecutionContext;
+
+thread_local! {
+    static CURRENT_EXECUTION: RefCell<Option<CurrentExecution>> = const { RefCell::new(None) };
+}
+
+/// Execution a plugin call on this thread belongs to
+#[derive(Clone, Serialize)]
+pub(crate) struct CurrentExecution {
+    pub(crate) execution_id: String,
+    pub(crate) workflow: String,
+    pub(crate) stage: String,
+    pub(crate) trigger: String,
+    pub(crate) replay_of: Option<String>,
+    pub(crate) traceparent: Option<String>,
+
+    /// Most verbose plugin log level of the stage
+    #[serde(skip)]
+    pub(crate) log_level: Option<LevelFilter>,
+}
+
+/// Runs a plugin call with the execution visible to host functions
+pub(crate) fn with_current_execution<T>(
+    context: &ExecutionContext,
+    workflow: &str,
+    stage: &str,
+    log_level: Option<LevelFilter>,
+    f: impl FnOnce() -> T,
+) -> T {
+    let previous = CURRENT_EXECUTION.replace(Some(CurrentExecution {
+        execution_id: context.id.clone(),
+        workflow: workflow.to_string(),
+        stage: stage.to_string(),
+        trigger: context.trigger.clone(),
+        replay_of: context.replay_of.clone(),
+        traceparent: context.traceparent.clone(),
+        log_level,
+    }));
+
+    let res = f();
+
+    CURRENT_EXECUTION.set(previous);
+
+    res
+}
+
+/// Looks up the execution of the plugin call running on this thread, if any
+pub(crate) fn current_execution<T>(f: impl FnOnce(Option<&CurrentExecution>) -> T) -> T {
+    CURRENT_EXECUTION.with_borrow(|current| f(current.as_ref()))
+}
+
+/// Returns the current execution as JSON, e.g. `{ "execution_id": ..., "stage": ... }`
+pub(super) fn deadlift_execution(
+    plugin: &mut CurrentPlugin,
+    _inputs: &[Val],
+    outputs: &mut [Val],
+    _user_data: UserData<()>,
+) -> Result<(), extism::Error> {
+    let _entered = info_span!("host_function", name = "deadlift_execution").entered();
+
+    let output = CURRENT_EXECUTION.with_borrow(serde_json::to_string)?;
+    plugin.memory_set_val(&mut outputs[0], output)?;
+
+    Ok(())
+}
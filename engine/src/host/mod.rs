@@ -0,0 +1,78 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use extism::{Function, UserData, PTR};
+
+use crate::config::{PostgresConnectionConfig, WorkflowConfig};
+
+mod execution;
+use execution::deadlift_execution;
+pub(crate) use execution::{current_execution, with_current_execution};
+
+mod postgres;
+use postgres::{pg_execute, pg_query, PostgresHostContext, PostgresPools};
+
+/// Host functions made available to plugins, scoped per stage
+#[derive(Clone, Default)]
+pub struct HostFunctions {
+    postgres_pools: Arc<PostgresPools>,
+    postgres_connections: HashMap<String, HashSet<String>>,
+}
+
+impl HostFunctions {
+    pub fn new(
+        postgres_config: &HashMap<String, PostgresConnectionConfig>,
+        workflow: &WorkflowConfig,
+    ) -> Self {
+        Self {
+            postgres_pools: Arc::new(PostgresPools::new(postgres_config)),
+            postgres_connections: workflow
+                .graph
+                .node_weights()
+                .map(|stage| {
+                    (
+                        stage.id().to_string(),
+                        stage.postgres_connections.iter().cloned().collect(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn for_stage(&self, stage_id: &str) -> Vec<Function> {
+        let postgres_context = PostgresHostContext {
+            pools: self.postgres_pools.clone(),
+            allowed_connections: self
+                .postgres_connections
+                .get(stage_id)
+                .cloned()
+                .unwrap_or_default(),
+        };
+
+        vec![
+            Function::new(
+                "deadlift_execution",
+                [],
+                [PTR],
+                UserData::new(()),
+                deadlift_execution,
+            ),
+            Function::new(
+                "pg_query",
+                [PTR],
+                [PTR],
+                UserData::new(postgres_context.clone()),
+                pg_query,
+            ),
+            Function::new(
+                "pg_execute",
+                [PTR],
+                [PTR],
+                UserData::new(postgres_context),
+                pg_execute,
+            ),
+        ]
+    }
+}
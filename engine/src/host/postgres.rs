@@ -0,0 +1,542 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::{Deref, DerefMut},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use extism::{CurrentPlugin, UserData, Val};
+use postgres::{
+    types::{ToSql, Type},
+    Client, NoTls, Row,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tracing::{info_span, warn};
+
+use crate::config::PostgresConnectionConfig;
+
+const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Input of `pg_query` and `pg_execute`
+#[derive(Deserialize)]
+struct StatementInput {
+    connection: String,
+    sql: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+#[derive(Clone)]
+pub(super) struct PostgresHostContext {
+    pub(super) pools: Arc<PostgresPools>,
+    pub(super) allowed_connections: HashSet<String>,
+}
+
+impl PostgresHostContext {
+    fn run<T>(
+        &self,
+        input: &str,
+        f: impl FnOnce(&mut Client, &postgres::Statement, &[&(dyn ToSql + Sync)]) -> Result<T>,
+    ) -> Result<T> {
+        let input = serde_json::from_str::<StatementInput>(input)?;
+
+        if !self.allowed_connections.contains(&input.connection) {
+            return Err(anyhow!(
+                "stage is not allowed to use postgres connection '{}'",
+                input.connection
+            ));
+        }
+
+        let pool = self
+            .pools
+            .get(&input.connection)
+            .ok_or_else(|| anyhow!("unknown postgres connection '{}'", input.connection))?;
+
+        let mut client = pool.checkout()?;
+        let statement = client.prepare(&input.sql)?;
+
+        if statement.params().len() != input.params.len() {
+            return Err(anyhow!(
+                "statement expects {} params, got {}",
+                statement.params().len(),
+                input.params.len()
+            ));
+        }
+
+        let params = statement
+            .params()
+            .iter()
+            .zip(&input.params)
+            .map(|(ty, value)| to_sql_param(ty, value))
+            .collect::<Result<Vec<_>>>()?;
+        let param_refs = params
+            .iter()
+            .map(|param| param.as_ref() as &(dyn ToSql + Sync))
+            .collect::<Vec<_>>();
+
+        f(&mut client, &statement, &param_refs)
+    }
+}
+
+/// Runs a statement and returns its rows as a JSON array of objects
+pub(super) fn pg_query(
+    plugin: &mut CurrentPlugin,
+    inputs: &[Val],
+    outputs: &mut [Val],
+    user_data: UserData<PostgresHostContext>,
+) -> Result<(), extism::Error> {
+    let _entered = info_span!("host_function", name = "pg_query").entered();
+
+    let input: String = plugin.memory_get_val(&inputs[0])?;
+    let context = user_data.get()?.lock().unwrap().clone();
+
+    let rows = context.run(&input, |client, statement, params| {
+        Ok(client.query(statement, params)?)
+    })?;
+
+    let rows = rows.iter().map(row_to_json).collect::<Result<Vec<_>>>()?;
+    let output = Value::Array(rows).to_string();
+    plugin.memory_set_val(&mut outputs[0], output)?;
+
+    Ok(())
+}
+
+/// Runs a statement and returns `{ "rows_affected": <n> }`
+pub(super) fn pg_execute(
+    plugin: &mut CurrentPlugin,
+    inputs: &[Val],
+    outputs: &mut [Val],
+    user_data: UserData<PostgresHostContext>,
+) -> Result<(), extism::Error> {
+    let _entered = info_span!("host_function", name = "pg_execute").entered();
+
+    let input: String = plugin.memory_get_val(&inputs[0])?;
+    let context = user_data.get()?.lock().unwrap().clone();
+
+    let rows_affected = context.run(&input, |client, statement, params| {
+        Ok(client.execute(statement, params)?)
+    })?;
+
+    let output = json!({ "rows_affected": rows_affected }).to_string();
+    plugin.memory_set_val(&mut outputs[0], output)?;
+
+    Ok(())
+}
+
+/// Converts a JSON param to the type the statement expects
+///
+/// Types without a JSON equivalent are passed as text, so the statement can cast them, e.g.
+/// `$1::text::uuid`
+fn to_sql_param(ty: &Type, value: &Value) -> Result<Box<dyn ToSql + Sync>> {
+    let invalid = || anyhow!("invalid value {value} for param of type {ty}");
+
+    if value.is_null() {
+        return Ok(Box::new(None::<String>));
+    }
+
+    Ok(match *ty {
+        Type::BOOL => Box::new(value.as_bool().ok_or_else(invalid)?),
+        Type::INT2 => Box::new(i16::try_from(value.as_i64().ok_or_else(invalid)?)?),
+        Type::INT4 => Box::new(i32::try_from(value.as_i64().ok_or_else(invalid)?)?),
+        Type::INT8 => Box::new(value.as_i64().ok_or_else(invalid)?),
+        Type::FLOAT4 => Box::new(value.as_f64().ok_or_else(invalid)? as f32),
+        Type::FLOAT8 => Box::new(value.as_f64().ok_or_else(invalid)?),
+        Type::JSON | Type::JSONB => Box::new(value.clone()),
+        _ => match value {
+            Value::String(s) => Box::new(s.clone()),
+            other => Box::new(other.to_string()),
+        },
+    })
+}
+
+/// Converts a row to a JSON object; timestamps become RFC 3339 strings, and columns of other
+/// types without a JSON equivalent must be cast to text in the query
+fn row_to_json(row: &Row) -> Result<Value> {
+    let mut object = Map::new();
+
+    for (idx, column) in row.columns().iter().enumerate() {
+        let value = match *column.type_() {
+            Type::BOOL => row.try_get::<_, Option<bool>>(idx)?.map(Value::from),
+            Type::INT2 => row.try_get::<_, Option<i16>>(idx)?.map(Value::from),
+            Type::INT4 => row.try_get::<_, Option<i32>>(idx)?.map(Value::from),
+            Type::INT8 => row.try_get::<_, Option<i64>>(idx)?.map(Value::from),
+            Type::FLOAT4 => row.try_get::<_, Option<f32>>(idx)?.map(Value::from),
+            Type::FLOAT8 => row.try_get::<_, Option<f64>>(idx)?.map(Value::from),
+            Type::JSON | Type::JSONB => row.try_get::<_, Option<Value>>(idx)?,
+            Type::TIMESTAMP | Type::TIMESTAMPTZ => row
+                .try_get::<_, Option<SystemTime>>(idx)?
+                .map(|timestamp| Value::from(DateTime::<Utc>::from(timestamp).to_rfc3339())),
+            _ => row
+                .try_get::<_, Option<String>>(idx)
+                .map_err(|_| {
+                    anyhow!(
+                        "column '{}' of type {} has no JSON equivalent; cast it to text in the query",
+                        column.name(),
+                        column.type_()
+                    )
+                })?
+                .map(Value::from),
+        };
+
+        object.insert(column.name().to_string(), value.unwrap_or(Value::Null));
+    }
+
+    Ok(Value::Object(object))
+}
+
+/// Named connection pools shared by every stage
+#[derive(Default)]
+pub(super) struct PostgresPools(HashMap<String, PostgresPool>);
+
+impl PostgresPools {
+    pub(super) fn new(config: &HashMap<String, PostgresConnectionConfig>) -> Self {
+        Self(
+            config
+                .iter()
+                .map(|(name, connection)| (name.clone(), PostgresPool::new(connection)))
+                .collect(),
+        )
+    }
+
+    fn get(&self, name: &str) -> Option<&PostgresPool> {
+        self.0.get(name)
+    }
+}
+
+/// Connections a [`PostgresPool`] can hold
+trait Connection: Sized {
+    fn connect(url: &str) -> Result<Self>;
+
+    fn is_closed(&self) -> bool;
+
+    /// Ends any transaction and session state a plugin left behind, before the connection is
+    /// checked out again
+    fn reset(&mut self) -> Result<()>;
+}
+
+impl Connection for Client {
+    fn connect(url: &str) -> Result<Self> {
+        Ok(Client::connect(url, NoTls)?)
+    }
+
+    fn is_closed(&self) -> bool {
+        Client::is_closed(self)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // `DISCARD ALL` would also drop the statements the client prepared and caches
+        self.batch_execute("ROLLBACK")?;
+        self.batch_execute(
+            "CLOSE ALL; RESET ALL; UNLISTEN *; SELECT pg_advisory_unlock_all(); DISCARD TEMP",
+        )?;
+        Ok(())
+    }
+}
+
+struct PostgresPool<C = Client> {
+    url: String,
+    max_connections: usize,
+    checkout_timeout: Duration,
+    state: Mutex<PoolState<C>>,
+    available: Condvar,
+}
+
+struct PoolState<C> {
+    idle: Vec<C>,
+    open: usize,
+}
+
+impl<C: Connection> PostgresPool<C> {
+    fn new(config: &PostgresConnectionConfig) -> Self {
+        Self {
+            url: config.url.clone(),
+            max_connections: config.max_connections.max(1),
+            checkout_timeout: CHECKOUT_TIMEOUT,
+            state: Mutex::new(PoolState {
+                idle: vec![],
+                open: 0,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Reuses an idle connection, opens a new one below the limit, or waits for one to be returned
+    fn checkout(&self) -> Result<PooledClient<'_, C>> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(client) = state.idle.pop() {
+                if client.is_closed() {
+                    state.open -= 1;
+                    continue;
+                }
+
+                return Ok(PooledClient {
+                    pool: self,
+                    client: Some(client),
+                });
+            }
+
+            if state.open < self.max_connections {
+                state.open += 1;
+                drop(state);
+
+                return match C::connect(&self.url) {
+                    Ok(client) => Ok(PooledClient {
+                        pool: self,
+                        client: Some(client),
+                    }),
+                    Err(e) => {
+                        self.state.lock().unwrap().open -= 1;
+                        self.available.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+
+            let (next_state, timeout) = self
+                .available
+                .wait_timeout(state, self.checkout_timeout)
+                .unwrap();
+            state = next_state;
+
+            if timeout.timed_out() {
+                return Err(anyhow!("timed out waiting for a postgres connection"));
+            }
+        }
+    }
+}
+
+struct PooledClient<'a, C: Connection = Client> {
+    pool: &'a PostgresPool<C>,
+    client: Option<C>,
+}
+
+impl<C: Connection> Deref for PooledClient<'_, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl<C: Connection> DerefMut for PooledClient<'_, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl<C: Connection> Drop for PooledClient<'_, C> {
+    /// Returns the connection once reset, or closes it when the reset fails
+    fn drop(&mut self) {
+        let Some(mut client) = self.client.take() else {
+            return;
+        };
+
+        let reset = client.reset();
+        let mut state = self.pool.state.lock().unwrap();
+        match reset {
+            Ok(()) => state.idle.push(client),
+            Err(e) => {
+                warn!("closing postgres connection that failed to reset; {e}");
+                state.open -= 1;
+            }
+        }
+        drop(state);
+
+        self.pool.available.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(0);
+
+    struct FakeConnection {
+        id: usize,
+        closed: bool,
+        in_transaction: bool,
+        fail_reset: bool,
+    }
+
+    impl Connection for FakeConnection {
+        fn connect(url: &str) -> Result<Self> {
+            if url == "unreachable" {
+                return Err(anyhow!("connection refused"));
+            }
+
+            Ok(Self {
+                id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst),
+                closed: false,
+                in_transaction: false,
+                fail_reset: false,
+            })
+        }
+
+        fn is_closed(&self) -> bool {
+            self.closed
+        }
+
+        fn reset(&mut self) -> Result<()> {
+            if self.fail_reset {
+                return Err(anyhow!("connection lost"));
+            }
+
+            self.in_transaction = false;
+            Ok(())
+        }
+    }
+
+    fn connection_config(url: &str, max_connections: usize) -> PostgresConnectionConfig {
+        serde_yaml::from_str(&format!(
+            "{{ url: {url}, max_connections: {max_connections} }}"
+        ))
+        .unwrap()
+    }
+
+    fn fake_pool(url: &str, max_connections: usize) -> PostgresPool<FakeConnection> {
+        let mut pool = PostgresPool::new(&connection_config(url, max_connections));
+        pool.checkout_timeout = Duration::from_millis(50);
+        pool
+    }
+
+    #[test]
+    fn test_pool_limits_and_reuses_connections() {
+        let pool = fake_pool("fake", 2);
+
+        let first = pool.checkout().unwrap();
+        let second = pool.checkout().unwrap();
+        assert_ne!(first.id, second.id);
+
+        let err = pool.checkout().err().unwrap();
+        assert!(err.to_string().contains("timed out"));
+
+        let first_id = first.id;
+        drop(first);
+        assert_eq!(pool.checkout().unwrap().id, first_id);
+        assert_eq!(pool.state.lock().unwrap().open, 2);
+    }
+
+    #[test]
+    fn test_pool_waits_for_returned_connection() {
+        let mut pool = fake_pool("fake", 1);
+        pool.checkout_timeout = Duration::from_secs(5);
+
+        let client = pool.checkout().unwrap();
+        let id = client.id;
+
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| pool.checkout().map(|client| client.id));
+
+            std::thread::sleep(Duration::from_millis(50));
+            drop(client);
+
+            assert_eq!(waiting.join().unwrap().unwrap(), id);
+        });
+    }
+
+    #[test]
+    fn test_pool_replaces_closed_and_failed_connections() {
+        let pool = fake_pool("fake", 1);
+
+        let mut client = pool.checkout().unwrap();
+        let id = client.id;
+        client.closed = true;
+        drop(client);
+
+        assert_ne!(pool.checkout().unwrap().id, id);
+        assert_eq!(pool.state.lock().unwrap().open, 1);
+
+        let pool = fake_pool("unreachable", 1);
+        assert!(pool.checkout().is_err());
+        assert_eq!(pool.state.lock().unwrap().open, 0);
+    }
+
+    #[test]
+    fn test_pool_resets_returned_connections() {
+        let pool = fake_pool("fake", 1);
+
+        // e.g. a plugin that ran `BEGIN` and never committed
+        let mut client = pool.checkout().unwrap();
+        let id = client.id;
+        client.in_transaction = true;
+        drop(client);
+
+        let mut client = pool.checkout().unwrap();
+        assert_eq!(client.id, id);
+        assert!(!client.in_transaction);
+
+        // a connection that can't be reset is closed rather than reused
+        client.fail_reset = true;
+        drop(client);
+
+        assert_eq!(pool.state.lock().unwrap().open, 0);
+        assert_ne!(pool.checkout().unwrap().id, id);
+    }
+
+    #[test]
+    fn test_allowed_connections() {
+        let context = PostgresHostContext {
+            pools: Arc::new(PostgresPools::new(&HashMap::from([(
+                "app".to_string(),
+                connection_config("postgres://localhost:1/app", 1),
+            )]))),
+            allowed_connections: HashSet::from(["app".to_string(), "reporting".to_string()]),
+        };
+
+        let run = |connection: &str| {
+            context
+                .run(
+                    &json!({ "connection": connection, "sql": "select 1" }).to_string(),
+                    |_, _, _| Ok(()),
+                )
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(run("billing").contains("not allowed to use postgres connection 'billing'"));
+        assert!(run("reporting").contains("unknown postgres connection 'reporting'"));
+    }
+
+    /// Runs with `cargo test -- --ignored` against `DEADLIFT_TEST_POSTGRES_URL`
+    #[test]
+    #[ignore = "requires DEADLIFT_TEST_POSTGRES_URL"]
+    fn test_row_to_json() {
+        let url = std::env::var("DEADLIFT_TEST_POSTGRES_URL")
+            .expect("DEADLIFT_TEST_POSTGRES_URL is required for postgres tests");
+        let mut client = Client::connect(&url, NoTls).unwrap();
+
+        let row = client
+            .query_one(
+                "SELECT true AS b, 1::int4 AS i, 1.5::float8 AS f, 'a'::text AS t, \
+                    '{\"a\":1}'::jsonb AS j, NULL::int8 AS n, \
+                    '2024-03-01 10:00:00+00'::timestamptz AS ts, 1.5::numeric::text AS d",
+                &[],
+            )
+            .unwrap();
+        assert_eq!(
+            row_to_json(&row).unwrap(),
+            json!({
+                "b": true,
+                "i": 1,
+                "f": 1.5,
+                "t": "a",
+                "j": { "a": 1 },
+                "n": null,
+                "ts": "2024-03-01T10:00:00+00:00",
+                "d": "1.5",
+            })
+        );
+
+        let row = client.query_one("SELECT 1.5::numeric AS d", &[]).unwrap();
+        let err = row_to_json(&row).unwrap_err();
+        assert!(err.to_string().contains("column 'd' of type numeric"));
+    }
+}
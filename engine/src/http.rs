@@ -0,0 +1,476 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Map, Value};
+use sha2::Sha256;
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::error;
+
+use crate::{
+    config::{HttpConfig, WebhookMode, WebhookRoute},
+    error::ExecutionError,
+    executor::{validate_execution_id, ExecutionContext},
+    health::Health,
+    metrics::METRICS,
+    nats::{EXECUTION_ID_HEADER, TRACEPARENT_HEADER},
+    shutdown::Shutdown,
+    workflows::Workflows,
+};
+
+struct HttpState {
+    webhooks: HashMap<String, WebhookRoute>,
+    workflows: Arc<Workflows>,
+    health: Arc<Health>,
+
+    /// Executions of `async` webhooks, waited for before the server thread ends
+    executions: Mutex<JoinSet<()>>,
+}
+
+pub async fn start_http_thread(
+    config: HttpConfig,
+    workflows: Arc<Workflows>,
+    health: Arc<Health>,
+    shutdown: Shutdown,
+) -> Result<Option<JoinHandle<()>>> {
+    let Some(listen) = config.listen else {
+        return Ok(None);
+    };
+
+    let state = Arc::new(HttpState {
+        webhooks: config
+            .webhooks
+            .into_iter()
+            .map(|route| (route.workflow.clone(), route))
+            .collect(),
+        workflows,
+        health,
+        executions: Mutex::new(JoinSet::new()),
+    });
+
+    let router = Router::new()
+        .route("/hooks/:workflow", post(handle_webhook))
+        .route("/metrics", get(handle_metrics))
+        .route("/healthz", get(handle_healthz))
+        .route("/readyz", get(handle_readyz))
+        .with_state(state.clone());
+
+    let listener = tokio::net::TcpListener::bind(&listen).await?;
+
+    Ok(Some(tokio::task::spawn(async move {
+        // in-flight webhook requests are answered before the server stops
+        if let Err(e) = axum::serve(listener, router)
+            .with_graceful_shutdown(shutdown.draining())
+            .await
+        {
+            error!("http server stopped; {e}");
+        }
+
+        // accepted executions finish too; they are aborted with this thread after the grace period
+        wait_for_executions(&state).await;
+    })))
+}
+
+async fn wait_for_executions(state: &HttpState) {
+    let mut executions = std::mem::take(&mut *state.executions.lock().unwrap());
+    while executions.join_next().await.is_some() {}
+}
+
+async fn handle_metrics() -> Response {
+    match METRICS.encode() {
+        Ok(body) => body.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn handle_healthz(State(state): State<Arc<HttpState>>) -> Response {
+    let report = state.health.report();
+    let status = if report.live {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(report)).into_response()
+}
+
+async fn handle_readyz(State(state): State<Arc<HttpState>>) -> Response {
+    let report = state.health.report();
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(report)).into_response()
+}
+
+async fn handle_webhook(
+    State(state): State<Arc<HttpState>>,
+    Path(workflow): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some(route) = state.webhooks.get(&workflow) else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("no webhook for '{workflow}'"),
+        )
+            .into_response();
+    };
+
+    let Some(executor) = state.workflows.get(&workflow) else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("workflow '{workflow}' is not loaded"),
+        )
+            .into_response();
+    };
+
+    if let Some(secret) = &route.secret {
+        if !verify_signature(secret, &route.signature_header, &headers, &body) {
+            return (StatusCode::UNAUTHORIZED, "invalid signature").into_response();
+        }
+    }
+
+    let input = match serde_json::to_vec(&webhook_input(&headers, query, &body)) {
+        Ok(input) => input,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    let context = execution_context(&headers);
+    if let Err(e) = validate_execution_id(&context.id) {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    let response_headers = context_headers(&context);
+
+    match route.mode {
+        WebhookMode::Sync => match executor.execute_async(context, input).await {
+            Ok(output) => (StatusCode::OK, response_headers, output).into_response(),
+            Err(e) => {
+                let status = if e.downcast_ref::<ExecutionError>().is_some() {
+                    StatusCode::BAD_REQUEST
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                };
+                (status, response_headers, e.to_string()).into_response()
+            }
+        },
+        WebhookMode::Async => {
+            let execution_id = context.id.clone();
+
+            let id = execution_id.clone();
+            {
+                let mut executions = state.executions.lock().unwrap();
+                while executions.try_join_next().is_some() {}
+                executions.spawn(async move {
+                    if let Err(e) = executor.execute_async(context, input).await {
+                        error!("webhook execution {id} failed; {e}");
+                    }
+                });
+            }
+
+            (
+                StatusCode::ACCEPTED,
+                response_headers,
+                Json(json!({ "execution_id": execution_id })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Keeps a caller supplied execution id and trace context
+fn execution_context(headers: &HeaderMap) -> ExecutionContext {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+
+    let mut context = ExecutionContext::new("webhook");
+    if let Some(id) = header(EXECUTION_ID_HEADER) {
+        context.id = id;
+    }
+    context.traceparent = header(TRACEPARENT_HEADER);
+
+    context
+}
+
+fn context_headers(context: &ExecutionContext) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    let values = [
+        (EXECUTION_ID_HEADER, Some(context.id.as_str())),
+        (TRACEPARENT_HEADER, context.traceparent.as_deref()),
+    ];
+    for (name, value) in values {
+        // header names are lowercased; `from_static` would reject the mixed case constants
+        if let (Ok(name), Some(Ok(value))) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            value.map(HeaderValue::from_str),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+
+    headers
+}
+
+/// Plugin input carrying the request headers, query parameters and body
+///
+/// JSON bodies are embedded as is, any other body as a string
+fn webhook_input(headers: &HeaderMap, query: HashMap<String, String>, body: &[u8]) -> Value {
+    let headers = headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.as_str().to_string(),
+                Value::String(String::from_utf8_lossy(value.as_bytes()).to_string()),
+            )
+        })
+        .collect::<Map<String, Value>>();
+
+    let body = serde_json::from_slice::<Value>(body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).to_string()));
+
+    json!({
+        "headers": headers,
+        "query": query,
+        "body": body,
+    })
+}
+
+fn verify_signature(secret: &str, header: &str, headers: &HeaderMap, body: &[u8]) -> bool {
+    let Some(signature) = headers.get(header).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+
+    let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+    mac.update(body);
+
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::{config::WorkflowConfig, executor::Executor};
+
+    /// Outputs its input, through the extism kernel
+    const ECHO_PLUGIN_WAT: &str = r#"
+        (module
+          (import "extism:host/env" "input_length" (func $input_length (result i64)))
+          (import "extism:host/env" "input_load_u8" (func $input_load_u8 (param i64) (result i32)))
+          (import "extism:host/env" "alloc" (func $alloc (param i64) (result i64)))
+          (import "extism:host/env" "store_u8" (func $store_u8 (param i64 i32)))
+          (import "extism:host/env" "output_set" (func $output_set (param i64 i64)))
+          (func (export "echo") (result i32)
+            (local $len i64)
+            (local $offset i64)
+            (local $i i64)
+            (local.set $len (call $input_length))
+            (local.set $offset (call $alloc (local.get $len)))
+            (block $done
+              (loop $copy
+                (br_if $done (i64.ge_u (local.get $i) (local.get $len)))
+                (call $store_u8
+                  (i64.add (local.get $offset) (local.get $i))
+                  (call $input_load_u8 (local.get $i)))
+                (local.set $i (i64.add (local.get $i) (i64.const 1)))
+                (br $copy)))
+            (call $output_set (local.get $offset) (local.get $len))
+            (i32.const 0)))
+    "#;
+
+    /// State with a route to an echo workflow whose body requires `n`, and a route to a workflow
+    /// that isn't loaded
+    fn test_state(mode: WebhookMode, secret: Option<&str>) -> Arc<HttpState> {
+        let workflow = serde_yaml::from_str::<WorkflowConfig>(
+            "
+            name: echo
+            input_schema:
+              type: object
+              required: [body]
+              properties:
+                body:
+                  type: object
+                  required: [n]
+            nodes:
+              - object_name: echo
+                plugin_function_name: echo
+            node_holes: []
+            edge_property: directed
+            edges: []
+            ",
+        )
+        .unwrap();
+
+        let pool = extism::Pool::new(1);
+        pool.add_builder(
+            String::from("echo"),
+            extism::PluginBuilder::new(extism::Manifest::new([extism::Wasm::data(
+                ECHO_PLUGIN_WAT.as_bytes().to_vec(),
+            )])),
+        );
+
+        let workflows = Arc::new(Workflows::default());
+        workflows.insert_executor(Arc::new(Executor::new(workflow, pool).unwrap()));
+
+        let route = |workflow: &str| WebhookRoute {
+            workflow: workflow.to_string(),
+            secret: secret.map(String::from),
+            signature_header: String::from("X-Signature-256"),
+            mode,
+        };
+
+        Arc::new(HttpState {
+            webhooks: HashMap::from([
+                (String::from("echo"), route("echo")),
+                (String::from("unloaded"), route("unloaded")),
+            ]),
+            workflows,
+            health: Arc::new(Health::with_nats_state(|| true, true)),
+            executions: Mutex::new(JoinSet::new()),
+        })
+    }
+
+    async fn post(state: &Arc<HttpState>, workflow: &str, body: &str) -> (StatusCode, Bytes) {
+        post_with_headers(state, workflow, HeaderMap::new(), body).await
+    }
+
+    async fn post_with_headers(
+        state: &Arc<HttpState>,
+        workflow: &str,
+        headers: HeaderMap,
+        body: &str,
+    ) -> (StatusCode, Bytes) {
+        let response = handle_webhook(
+            State(state.clone()),
+            Path(workflow.to_string()),
+            Query(HashMap::new()),
+            headers,
+            Bytes::from(body.to_string()),
+        )
+        .await;
+
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn test_webhook_sync() {
+        let state = test_state(WebhookMode::Sync, None);
+
+        let (status, body) = post(&state, "echo", r#"{"n":1}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        let output = serde_json::from_slice::<Value>(&body).unwrap();
+        assert_eq!(output["body"], json!({ "n": 1 }));
+    }
+
+    #[tokio::test]
+    async fn test_webhook_async() {
+        let state = test_state(WebhookMode::Async, None);
+
+        let (status, body) = post(&state, "echo", r#"{"n":1}"#).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let body = serde_json::from_slice::<Value>(&body).unwrap();
+        assert!(body["execution_id"].is_string());
+
+        // the accepted execution is tracked until the server thread waits for it
+        assert_eq!(state.executions.lock().unwrap().len(), 1);
+        wait_for_executions(&state).await;
+        assert!(state.executions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_webhook_not_found() {
+        let state = test_state(WebhookMode::Sync, None);
+
+        let (status, _) = post(&state, "missing", "{}").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = post(&state, "unloaded", "{}").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_bad_signature() {
+        let state = test_state(WebhookMode::Sync, Some("secret"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-signature-256", HeaderValue::from_static("sha256=00"));
+
+        let (status, _) = post_with_headers(&state, "echo", headers, r#"{"n":1}"#).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_schema_violation() {
+        let state = test_state(WebhookMode::Sync, None);
+
+        let (status, body) = post(&state, "echo", r#"{"m":1}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(String::from_utf8_lossy(&body).starts_with("schema_violation"));
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let body = br#"{"event":"created"}"#;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-signature-256",
+            HeaderValue::from_str(&format!("sha256={signature}")).unwrap(),
+        );
+
+        assert!(verify_signature(
+            "secret",
+            "X-Signature-256",
+            &headers,
+            body
+        ));
+        assert!(!verify_signature(
+            "other",
+            "X-Signature-256",
+            &headers,
+            body
+        ));
+        assert!(!verify_signature(
+            "secret",
+            "X-Hub-Signature",
+            &headers,
+            body
+        ));
+    }
+}
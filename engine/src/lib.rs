@@ -1,19 +1,162 @@
-use std::sync::LazyLock;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock,
+    },
+    time::Duration,
+};
 
-use anyhow::Result;
-use config::require_config;
-use extism::Wasm;
-use nats::{require_nats, start_execution_thread};
-use plugin::require_plugin_pool;
-use tokio::{io::AsyncReadExt, task::JoinHandle};
+use anyhow::{Error, Result};
+use cache::ModuleCache;
+use config::{require_config, PluginConfig, PostgresConnectionConfig, WorkflowConfig};
+use executor::{ExecutionContext, Executor};
+use health::Health;
+use history::HistoryStore;
+use host::HostFunctions;
+use logs::start_log_publisher_thread;
+use metrics::METRICS;
+use module::{load_workflow_modules, DefaultModuleLoader};
+use nats::require_nats;
+use plugin::new_plugin_pool;
+use registry::{start_heartbeat_thread, AgentRecord};
+use runtime::supervise;
+use service::start_service_thread;
+use shutdown::{shutdown_channel, ShutdownController, STOP_TIMEOUT};
+use subjects::Subjects;
+use telemetry::{init_telemetry, shutdown_telemetry};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{info, warn};
+use workflows::{read_agent_workflows, start_workflow_watcher_thread, WorkflowManager, Workflows};
 
+pub mod cache;
 pub mod config;
+pub mod error;
+pub mod executor;
+pub mod health;
+pub mod history;
+pub mod host;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod logs;
+pub mod mapping;
+pub mod metrics;
+pub mod module;
 pub mod nats;
+pub mod placement;
 pub mod plugin;
+pub mod registry;
+pub mod runtime;
+pub mod schedule;
+pub mod schema;
+pub mod service;
+pub mod shutdown;
+pub mod subjects;
+pub mod telemetry;
+pub mod trigger;
 pub mod utils;
+pub mod workflows;
 
 pub struct EngineThreadHandles {
-    pub execution_handle_opt: Option<JoinHandle<()>>,
+    pub http_handle_opt: Option<JoinHandle<()>>,
+    pub log_handle_opt: Option<JoinHandle<()>>,
+    pub service_handle_opt: Option<JoinHandle<()>>,
+    pub heartbeat_handle_opt: Option<JoinHandle<()>>,
+    pub watcher_handle_opt: Option<JoinHandle<()>>,
+
+    /// Loaded workflows, with their execution, schedule and trigger threads
+    pub workflows: Arc<Workflows>,
+    shutdown: ShutdownController,
+    grace_period: Duration,
+    health: Arc<Health>,
+    nc: async_nats::Client,
+    fatal_rx: mpsc::UnboundedReceiver<Error>,
+}
+
+impl EngineThreadHandles {
+    /// Resolves with the error of the first supervised thread that keeps failing
+    pub async fn fatal(&mut self) -> Error {
+        match self.fatal_rx.recv().await {
+            Some(e) => e,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Stops accepting executions and waits up to the grace period for in-flight executions,
+    /// then abandons the rest, flushes nats and aborts the remaining threads
+    pub async fn shutdown(mut self) {
+        info!(
+            "draining in-flight executions for up to {}s",
+            self.grace_period.as_secs()
+        );
+        self.health.set_draining();
+        self.shutdown.drain();
+        let deadline = tokio::time::Instant::now() + self.grace_period;
+
+        // the watcher stops first, so that no workflow is loaded while draining
+        let agent_handles = [
+            &mut self.watcher_handle_opt,
+            &mut self.http_handle_opt,
+            &mut self.service_handle_opt,
+            &mut self.heartbeat_handle_opt,
+        ]
+        .into_iter()
+        .flatten();
+        let agent_drained =
+            tokio::time::timeout_at(deadline, futures::future::join_all(agent_handles))
+                .await
+                .is_ok();
+
+        let mut workflow_handles = self.workflows.take_handles();
+        let drained = agent_drained
+            && tokio::time::timeout_at(
+                deadline,
+                futures::future::join_all(workflow_handles.iter_mut()),
+            )
+            .await
+            .is_ok();
+
+        if !drained {
+            warn!("grace period elapsed; abandoning unfinished executions");
+            self.shutdown.stop();
+
+            let _ = tokio::time::timeout(
+                STOP_TIMEOUT,
+                futures::future::join_all(workflow_handles.iter_mut()),
+            )
+            .await;
+        }
+
+        if let Err(e) = self.nc.flush().await {
+            warn!("failed to flush nats; {e}");
+        }
+
+        for v in &workflow_handles {
+            v.abort()
+        }
+        self.abort();
+    }
+
+    pub fn abort(&self) {
+        if let Some(v) = &self.http_handle_opt {
+            v.abort()
+        }
+        if let Some(v) = &self.log_handle_opt {
+            v.abort()
+        }
+        if let Some(v) = &self.service_handle_opt {
+            v.abort()
+        }
+        if let Some(v) = &self.heartbeat_handle_opt {
+            v.abort()
+        }
+        if let Some(v) = &self.watcher_handle_opt {
+            v.abort()
+        }
+        self.workflows.abort();
+
+        shutdown_telemetry();
+    }
 }
 
 pub const MODULE_BUCKET_NAME: &str = "wasm";
@@ -22,56 +165,252 @@ pub const WORKFLOW_BUCKET_NAME: &str = "workflows";
 pub static DEFAULT_NATS_URL: LazyLock<&'static str> =
     LazyLock::new(|| option_env!("NATS_URL").unwrap_or("localhost:4222"));
 
+/// Counts reconnections of the agent's client in the `deadlift_nats_reconnects_total` metric
+fn count_reconnects(options: async_nats::ConnectOptions) -> async_nats::ConnectOptions {
+    let disconnected = Arc::new(AtomicBool::new(false));
+
+    options.event_callback(move |event| {
+        let disconnected = disconnected.clone();
+        async move {
+            match event {
+                async_nats::Event::Disconnected => disconnected.store(true, Ordering::Relaxed),
+                async_nats::Event::Connected => {
+                    if disconnected.swap(false, Ordering::Relaxed) {
+                        METRICS.nats_reconnected();
+                    }
+                }
+                _ => {}
+            }
+        }
+    })
+}
+
 // refactor into agent crate? then engine mainly exports call fn for embedded? or split that into another new sdk crate
 pub async fn run(config_bytes: Vec<u8>) -> Result<EngineThreadHandles> {
-    extism::set_log_callback(|v| print!("{}", v), "info")?;
-
     let config = require_config(config_bytes)?;
 
+    init_telemetry(&config.telemetry)?;
+
     // TODO-- move all object items into nats crate
-    let nc = require_nats(&config.nats).await?;
+    let nc = require_nats(
+        &config.nats,
+        count_reconnects(config.nats.connect_options().await?),
+    )
+    .await?;
 
+    let (shutdown_controller, shutdown) = shutdown_channel();
+    let (fatal_tx, fatal_rx) = mpsc::unbounded_channel();
+
+    let health = Arc::new(Health::new(nc.clone(), config.nats.enable_execution_thread));
     let js = async_nats::jetstream::new(nc.clone());
     let workflow_bucket = js.get_object_store(WORKFLOW_BUCKET_NAME).await?;
-    let module_bucket = js.get_object_store(MODULE_BUCKET_NAME).await?;
 
-    let mut workflow_object = workflow_bucket.get(&config.workflow.name).await?;
+    let agent_workflows = read_agent_workflows(&workflow_bucket, &config).await?;
+    health.set_workflow_loaded();
+
+    let loader = DefaultModuleLoader::new(
+        Some(js.clone()),
+        ModuleCache::from_plugin_config(&config.plugin),
+    );
+    let history = if config.history.enabled {
+        Some(HistoryStore::open(&js, &config.history).await?)
+    } else {
+        None
+    };
+
+    let workflows = Arc::new(Workflows::default());
+    let manager = Arc::new(WorkflowManager {
+        workflows: workflows.clone(),
+        nc: nc.clone(),
+        js: js.clone(),
+        loader,
+        config: config.clone(),
+        history,
+        health: health.clone(),
+        shutdown: shutdown.clone(),
+        fatal: fatal_tx.clone(),
+    });
 
-    let mut workflow_bytes = vec![];
-    workflow_object.read_to_end(&mut workflow_bytes).await?;
+    for (workflow, digest) in agent_workflows {
+        let name = workflow.name.clone();
 
-    let workflow = serde_yaml::from_slice::<config::WorkflowConfig>(&workflow_bytes)?;
+        // only the workflow the agent is configured with fails the start
+        if let Err(e) = manager.start(workflow, digest).await {
+            if name == config.workflow.name {
+                return Err(e);
+            }
+            warn!("skipping workflow '{name}'; {e}");
+        }
+    }
+    health.set_modules_loaded();
 
-    let mut modules = vec![];
-    let mut module_names = std::collections::HashSet::new();
-    for idx in workflow.graph.node_indices() {
-        let stage = &workflow.graph[idx];
+    // registered once the workflows are loaded, as the service describes them
+    let service_handle_opt = Some(
+        supervise(
+            "service",
+            {
+                let nc = nc.clone();
+                let subjects = Subjects::new(&config.nats);
+                let health = health.clone();
+                let workflows = workflows.clone();
+                let shutdown = shutdown.clone();
 
-        let mut wasm_object = module_bucket.get(&stage.object_name).await?;
+                move || {
+                    start_service_thread(
+                        nc.clone(),
+                        subjects.clone(),
+                        health.clone(),
+                        workflows.clone(),
+                        shutdown.clone(),
+                    )
+                }
+            },
+            shutdown.clone(),
+            fatal_tx.clone(),
+        )
+        .await?,
+    );
 
-        let mut wasm_bytes = vec![];
-        wasm_object.read_to_end(&mut wasm_bytes).await?;
+    let heartbeat_handle_opt = Some(
+        supervise(
+            "heartbeat",
+            {
+                let js = js.clone();
+                let record = AgentRecord::new(&config.agent, vec![]);
+                let workflows = workflows.clone();
+                let health = health.clone();
+                let shutdown = shutdown.clone();
 
-        modules.push(Wasm::Data {
-            data: wasm_bytes,
-            meta: extism::WasmMetadata {
-                name: stage.namespace.clone(),
-                hash: stage.hash.clone(),
+                move || {
+                    start_heartbeat_thread(
+                        js.clone(),
+                        record.clone(),
+                        workflows.clone(),
+                        health.clone(),
+                        shutdown.clone(),
+                    )
+                }
             },
-        });
+            shutdown.clone(),
+            fatal_tx.clone(),
+        )
+        .await?,
+    );
 
-        module_names.insert(stage.object_name.clone());
-    }
+    let log_handle_opt = if config.telemetry.publish_logs {
+        Some(
+            start_log_publisher_thread(
+                nc.clone(),
+                Subjects::new(&config.nats),
+                single_workflow_name(&workflows),
+                Duration::from_secs(config.telemetry.logs_max_age_secs),
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+
+    let watcher_handle_opt = if config.nats.enable_watcher_thread {
+        Some(
+            supervise(
+                "workflow watcher",
+                {
+                    let manager = manager.clone();
+
+                    move || start_workflow_watcher_thread(workflow_bucket.clone(), manager.clone())
+                },
+                shutdown.clone(),
+                fatal_tx.clone(),
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+
+    #[cfg(feature = "http")]
+    let http_handle_opt = if config.http.listen.is_some() {
+        Some(
+            supervise(
+                "http",
+                {
+                    let http_config = config.http.clone();
+                    let workflows = workflows.clone();
+                    let health = health.clone();
+                    let shutdown = shutdown.clone();
 
-    let pool = require_plugin_pool(modules.clone(), &config.plugin).await?;
+                    move || {
+                        let start = http::start_http_thread(
+                            http_config.clone(),
+                            workflows.clone(),
+                            health.clone(),
+                            shutdown.clone(),
+                        );
 
-    let execution_handle_opt = if config.nats.enable_execution_thread {
-        Some(start_execution_thread(nc.clone(), pool).await)
+                        async move {
+                            start
+                                .await?
+                                .ok_or_else(|| anyhow::anyhow!("http server is disabled"))
+                        }
+                    }
+                },
+                shutdown,
+                fatal_tx,
+            )
+            .await?,
+        )
     } else {
         None
     };
+    #[cfg(not(feature = "http"))]
+    let http_handle_opt = {
+        if config.http.listen.is_some() {
+            tracing::warn!("http server is disabled; engine was built without the http feature");
+        }
+        None
+    };
 
     Ok(EngineThreadHandles {
-        execution_handle_opt,
+        http_handle_opt,
+        log_handle_opt,
+        service_handle_opt,
+        heartbeat_handle_opt,
+        watcher_handle_opt,
+        workflows,
+        shutdown: shutdown_controller,
+        grace_period: Duration::from_secs(config.shutdown.grace_period_secs),
+        health,
+        nc,
+        fatal_rx,
     })
 }
+
+/// Workflow of engine logs outside of executions, when the agent runs a single workflow
+fn single_workflow_name(workflows: &Workflows) -> Option<String> {
+    match workflows.executors().as_slice() {
+        [executor] => Some(executor.workflow().name.clone()),
+        _ => None,
+    }
+}
+
+/// Executes a workflow in-process, without nats
+///
+/// Stages cannot load modules from nats objects
+pub async fn run_local(
+    workflow_bytes: Vec<u8>,
+    plugin_config: &PluginConfig,
+    postgres_config: &HashMap<String, PostgresConnectionConfig>,
+    input: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let workflow = serde_yaml::from_slice::<WorkflowConfig>(&workflow_bytes)?;
+
+    let loader = DefaultModuleLoader::new(None, ModuleCache::from_plugin_config(plugin_config));
+    let modules = load_workflow_modules(&workflow, &loader).await?;
+    let host_functions = HostFunctions::new(postgres_config, &workflow);
+    let pool = new_plugin_pool(modules, plugin_config, &host_functions);
+    let executor = Executor::new(workflow, pool)?;
+
+    tokio::task::spawn_blocking(move || executor.execute(&ExecutionContext::new("local"), input))
+        .await?
+}
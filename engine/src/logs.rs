@@ -0,0 +1,333 @@
+use std::{fmt, sync::OnceLock, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_nats::jetstream::stream;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::{host::current_execution, metrics::METRICS, subjects::Subjects};
+
+/// Target of the tracing events extism emits for plugin log calls
+pub const PLUGIN_LOG_TARGET: &str = "extism::pdk";
+
+/// Stream retaining published logs, so that tails can start in the past
+pub const LOG_STREAM_NAME: &str = "DEADLIFT_LOGS";
+
+/// Logs waiting to be published before new ones are dropped, so a slow or disconnected NATS
+/// connection can't grow memory without bound
+const MAX_PENDING_LOGS: usize = 10_000;
+
+static LOG_SINK: OnceLock<LogSink> = OnceLock::new();
+
+struct LogSink {
+    sender: mpsc::Sender<LogRecord>,
+
+    /// Workflow of engine events outside of an execution; unset when the agent runs several, and
+    /// those events aren't published
+    workflow: Option<String>,
+}
+
+/// Log line, as published to [`Subjects::logs`]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub timestamp: DateTime<Utc>,
+    pub level: String,
+
+    /// `extism::pdk` for plugin logs, otherwise the engine module
+    pub target: String,
+    pub workflow: String,
+    pub stage: Option<String>,
+    pub execution_id: Option<String>,
+    pub message: String,
+}
+
+/// Applies per-stage plugin log levels and forwards plugin and engine logs to the log publisher
+///
+/// Plugin calls run on the calling thread, so the stage and execution of a plugin log event are
+/// those of the current execution on that thread. Engine events take them from the enclosing
+/// `execution` and `plugin_call` spans.
+pub(crate) struct LogLayer;
+
+/// Execution fields recorded on engine spans
+#[derive(Default)]
+struct SpanFields {
+    workflow: Option<String>,
+    execution_id: Option<String>,
+    stage: Option<String>,
+}
+
+impl Visit for SpanFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &value)
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = Some(format!("{value:?}").trim_matches('"').to_string());
+
+        match field.name() {
+            "workflow" => self.workflow = value,
+            "execution_id" => self.execution_id = value,
+            "stage" => self.stage = value,
+            _ => {}
+        }
+    }
+}
+
+impl<S> Layer<S> for LogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if !is_forwarded(attrs.metadata().target()) {
+            return;
+        }
+
+        let mut fields = SpanFields::default();
+        attrs.record(&mut fields);
+
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, _ctx: Context<'_, S>) -> bool {
+        if event.metadata().target() != PLUGIN_LOG_TARGET {
+            return true;
+        }
+
+        current_execution(|current| {
+            current
+                .and_then(|current| current.log_level)
+                .map_or(true, |log_level| *event.metadata().level() <= log_level)
+        })
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if !is_forwarded(event.metadata().target()) {
+            return;
+        }
+
+        let Some(sink) = LOG_SINK.get() else {
+            return;
+        };
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        let mut fields = SpanFields::default();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(span_fields) = span.extensions().get::<SpanFields>() {
+                    fields.workflow = fields.workflow.or(span_fields.workflow.clone());
+                    fields.execution_id = fields.execution_id.or(span_fields.execution_id.clone());
+                    fields.stage = fields.stage.or(span_fields.stage.clone());
+                }
+            }
+        }
+
+        current_execution(|current| {
+            if let Some(current) = current {
+                fields.workflow = Some(current.workflow.clone());
+                fields.execution_id = Some(current.execution_id.clone());
+                fields.stage = Some(current.stage.clone());
+            }
+        });
+
+        let Some(workflow) = fields.workflow.or_else(|| sink.workflow.clone()) else {
+            return;
+        };
+
+        let record = LogRecord {
+            timestamp: Utc::now(),
+            level: event.metadata().level().to_string(),
+            target: event.metadata().target().to_string(),
+            workflow,
+            stage: fields.stage,
+            execution_id: fields.execution_id,
+            message: visitor.message,
+        };
+
+        if sink.sender.try_send(record).is_err() {
+            METRICS.log_dropped();
+        }
+    }
+}
+
+/// Plugin logs and engine events are forwarded; dependency events are not
+fn is_forwarded(target: &str) -> bool {
+    target == PLUGIN_LOG_TARGET || target == "engine" || target.starts_with("engine::")
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        }
+    }
+}
+
+/// Publishes logs to [`Subjects::logs`], retained by the logs stream for `max_age`; can
+/// only be started once per process
+///
+/// Logs arriving while [`MAX_PENDING_LOGS`] are waiting are dropped and counted in the
+/// `deadlift_logs_dropped_total` metric.
+pub async fn start_log_publisher_thread(
+    nc: async_nats::Client,
+    subjects: Subjects,
+    workflow: Option<String>,
+    max_age: Duration,
+) -> Result<JoinHandle<()>> {
+    let js = async_nats::jetstream::new(nc.clone());
+    js.get_or_create_stream(stream::Config {
+        name: LOG_STREAM_NAME.to_string(),
+        subjects: vec![subjects.all_logs()],
+        max_age,
+        num_replicas: 1,
+        ..Default::default()
+    })
+    .await?;
+
+    let (sender, mut receiver) = mpsc::channel::<LogRecord>(MAX_PENDING_LOGS);
+
+    LOG_SINK
+        .set(LogSink { sender, workflow })
+        .map_err(|_| anyhow!("log publisher is already running"))?;
+
+    Ok(tokio::task::spawn(async move {
+        while let Some(record) = receiver.recv().await {
+            let Ok(payload) = serde_json::to_vec(&record) else {
+                continue;
+            };
+
+            // not logged; a failing publish would otherwise be reported through this layer
+            let _ = nc
+                .publish(subjects.logs(&record.workflow), payload.into())
+                .await;
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::{executor::ExecutionContext, host::with_current_execution};
+
+    struct CountingLayer(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for CountingLayer {
+        fn on_event(&self, _event: &Event<'_>, _ctx: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_stage_log_level() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let subscriber = tracing_subscriber::registry()
+            .with(LogLayer)
+            .with(CountingLayer(count.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let context = ExecutionContext::new("test");
+            let log_level = Some(tracing::level_filters::LevelFilter::WARN);
+
+            with_current_execution(&context, "math", "add_one", log_level, || {
+                tracing::info!(target: PLUGIN_LOG_TARGET, "dropped");
+                tracing::error!(target: PLUGIN_LOG_TARGET, "kept");
+                tracing::info!("engine events are not filtered");
+            });
+        });
+
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
+
+    /// Logs `hi` through the extism kernel, as the PDKs do
+    const LOG_PLUGIN_WAT: &str = r#"
+        (module
+          (import "extism:host/env" "alloc" (func $alloc (param i64) (result i64)))
+          (import "extism:host/env" "store_u8" (func $store_u8 (param i64 i32)))
+          (import "extism:host/env" "log_info" (func $log_info (param i64)))
+          (func (export "log") (result i32)
+            (local $offset i64)
+            (local.set $offset (call $alloc (i64.const 2)))
+            (call $store_u8 (local.get $offset) (i32.const 104))
+            (call $store_u8 (i64.add (local.get $offset) (i64.const 1)) (i32.const 105))
+            (call $log_info (local.get $offset))
+            (i32.const 0)))
+    "#;
+
+    /// Records the target and message of every event
+    struct CapturingLayer(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl<S: Subscriber> Layer<S> for CapturingLayer {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let mut visitor = MessageVisitor::default();
+            event.record(&mut visitor);
+
+            self.0
+                .lock()
+                .unwrap()
+                .push((event.metadata().target().to_string(), visitor.message));
+        }
+    }
+
+    #[test]
+    fn test_plugin_log_target() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let subscriber = tracing_subscriber::registry()
+            .with(LogLayer)
+            .with(CapturingLayer(events.clone()));
+
+        let mut plugin = extism::PluginBuilder::new(extism::Manifest::new([extism::Wasm::data(
+            LOG_PLUGIN_WAT.as_bytes().to_vec(),
+        )]))
+        .build()
+        .unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let context = ExecutionContext::new("test");
+
+            for log_level in [
+                tracing::level_filters::LevelFilter::INFO,
+                tracing::level_filters::LevelFilter::WARN,
+            ] {
+                with_current_execution(&context, "math", "add_one", Some(log_level), || {
+                    plugin.call::<Vec<u8>, Vec<u8>>("log", vec![]).unwrap();
+                });
+            }
+        });
+
+        // only the call at info level logs, so the stage level applies to extism's log events
+        let events = events.lock().unwrap();
+        let plugin_logs = events
+            .iter()
+            .filter(|(target, _)| target == PLUGIN_LOG_TARGET)
+            .collect::<Vec<_>>();
+        assert_eq!(plugin_logs.len(), 1);
+        assert!(plugin_logs[0].1.contains("hi"));
+    }
+}
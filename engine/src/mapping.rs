@@ -0,0 +1,301 @@
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+use serde_json_path::JsonPath;
+
+use crate::config::StageMapping;
+
+/// Stage input mapping compiled once when the workflow is loaded
+///
+/// Applied to the upstream output in order: `select`, `rename`, `merge_input`, `constants`.
+pub struct CompiledMapping {
+    select: Option<JsonPath>,
+    rename: Vec<(String, String)>,
+    merge_input: bool,
+    constants: Vec<(String, Template)>,
+}
+
+/// Constant value whose strings may contain `{{ <jsonpath> }}` templates
+enum Template {
+    Literal(Value),
+    /// A string that is exactly one template keeps the type of the matched value
+    Expression(JsonPath),
+    Interpolated(Vec<Segment>),
+    Array(Vec<Template>),
+    Object(Vec<(String, Template)>),
+}
+
+enum Segment {
+    Text(String),
+    Expression(JsonPath),
+}
+
+impl CompiledMapping {
+    pub fn compile(mapping: &StageMapping) -> Result<Self> {
+        let select = mapping.select.as_deref().map(parse_path).transpose()?;
+
+        let mut rename = mapping
+            .rename
+            .iter()
+            .map(|(from, to)| (from.clone(), to.clone()))
+            .collect::<Vec<_>>();
+        rename.sort();
+
+        let constants = mapping
+            .constants
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), Template::compile(value)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            select,
+            rename,
+            merge_input: mapping.merge_input,
+            constants,
+        })
+    }
+
+    /// Maps the upstream output into the stage input
+    pub fn apply(&self, upstream: &[u8], workflow_input: &[u8]) -> Result<Vec<u8>> {
+        let upstream = serde_json::from_slice::<Value>(upstream)
+            .map_err(|e| anyhow!("upstream output is not valid json; {e}"))?;
+
+        let mut value = match &self.select {
+            Some(path) => select(path, &upstream),
+            None => upstream.clone(),
+        };
+
+        if !self.rename.is_empty() {
+            let object = value
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("rename requires an object"))?;
+
+            // all fields are taken out before any is inserted, so swaps and chains see the
+            // original values
+            let renamed = self
+                .rename
+                .iter()
+                .filter_map(|(from, to)| Some((to, object.remove(from)?)))
+                .collect::<Vec<_>>();
+
+            for (to, field) in renamed {
+                object.insert(to.clone(), field);
+            }
+        }
+
+        let input = serde_json::from_slice::<Value>(workflow_input).unwrap_or(Value::Null);
+
+        if self.merge_input {
+            let input_object = input.as_object().ok_or_else(|| {
+                anyhow!("merge_input requires the workflow input to be an object")
+            })?;
+            let object = value
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("merge_input requires an object"))?;
+
+            for (key, field) in input_object {
+                object.entry(key.clone()).or_insert_with(|| field.clone());
+            }
+        }
+
+        if !self.constants.is_empty() {
+            let context = Value::Object(Map::from_iter([
+                (String::from("input"), input),
+                (String::from("upstream"), upstream),
+            ]));
+
+            let object = value
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("constants require an object"))?;
+
+            for (key, template) in &self.constants {
+                object.insert(key.clone(), template.render(&context));
+            }
+        }
+
+        Ok(serde_json::to_vec(&value)?)
+    }
+}
+
+impl Template {
+    fn compile(value: &Value) -> Result<Self> {
+        match value {
+            Value::String(s) => {
+                let segments = parse_segments(s)?;
+
+                match segments.as_slice() {
+                    [] | [Segment::Text(_)] => Ok(Template::Literal(value.clone())),
+                    [Segment::Expression(path)] => Ok(Template::Expression(path.clone())),
+                    _ => Ok(Template::Interpolated(segments)),
+                }
+            }
+            Value::Array(items) => Ok(Template::Array(
+                items.iter().map(Template::compile).collect::<Result<_>>()?,
+            )),
+            Value::Object(fields) => Ok(Template::Object(
+                fields
+                    .iter()
+                    .map(|(key, field)| Ok((key.clone(), Template::compile(field)?)))
+                    .collect::<Result<_>>()?,
+            )),
+            _ => Ok(Template::Literal(value.clone())),
+        }
+    }
+
+    fn render(&self, context: &Value) -> Value {
+        match self {
+            Template::Literal(value) => value.clone(),
+            Template::Expression(path) => select(path, context),
+            Template::Interpolated(segments) => {
+                let mut rendered = String::new();
+                for segment in segments {
+                    match segment {
+                        Segment::Text(text) => rendered.push_str(text),
+                        Segment::Expression(path) => match select(path, context) {
+                            Value::Null => {}
+                            Value::String(s) => rendered.push_str(&s),
+                            other => rendered.push_str(&other.to_string()),
+                        },
+                    }
+                }
+                Value::String(rendered)
+            }
+            Template::Array(items) => {
+                Value::Array(items.iter().map(|item| item.render(context)).collect())
+            }
+            Template::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(key, field)| (key.clone(), field.render(context)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Single matches are unwrapped, multiple matches become an array and no match is null
+fn select(path: &JsonPath, value: &Value) -> Value {
+    let nodes = path.query(value).all();
+
+    match nodes.as_slice() {
+        [] => Value::Null,
+        [node] => (*node).clone(),
+        _ => Value::Array(nodes.into_iter().cloned().collect()),
+    }
+}
+
+fn parse_path(path: &str) -> Result<JsonPath> {
+    JsonPath::parse(path).map_err(|e| anyhow!("invalid jsonpath '{path}'; {e}"))
+}
+
+fn parse_segments(s: &str) -> Result<Vec<Segment>> {
+    let mut segments = vec![];
+    let mut rest = s;
+
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .map(|end| start + end)
+            .ok_or_else(|| anyhow!("unterminated template in '{s}'"))?;
+
+        if start > 0 {
+            segments.push(Segment::Text(rest[..start].to_string()));
+        }
+        segments.push(Segment::Expression(parse_path(
+            rest[start + 2..end].trim(),
+        )?));
+
+        rest = &rest[end + 2..];
+    }
+
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest.to_string()));
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(mapping: &str, upstream: Value, input: Value) -> Value {
+        let mapping = serde_yaml::from_str::<StageMapping>(mapping).unwrap();
+        let output = CompiledMapping::compile(&mapping)
+            .unwrap()
+            .apply(
+                &serde_json::to_vec(&upstream).unwrap(),
+                &serde_json::to_vec(&input).unwrap(),
+            )
+            .unwrap();
+
+        serde_json::from_slice(&output).unwrap()
+    }
+
+    #[test]
+    fn test_apply_mapping() {
+        let output = apply(
+            "
+            select: $.user
+            rename:
+                id: user_id
+            merge_input: true
+            constants:
+                source: crm
+                authorization: Bearer {{ $.input.token }}
+                tags: '{{ $.upstream.tags[*] }}'
+            ",
+            serde_json::json!({ "user": { "id": 7, "name": "ada" }, "tags": ["a", "b"] }),
+            serde_json::json!({ "token": "abc", "name": "ignored" }),
+        );
+
+        assert_eq!(
+            output,
+            serde_json::json!({
+                "user_id": 7,
+                "name": "ada",
+                "token": "abc",
+                "source": "crm",
+                "authorization": "Bearer abc",
+                "tags": ["a", "b"]
+            })
+        );
+    }
+
+    #[test]
+    fn test_rename_swap_and_chain() {
+        let output = apply(
+            "
+            rename:
+                a: b
+                b: a
+            ",
+            serde_json::json!({ "a": 1, "b": 2 }),
+            serde_json::json!({}),
+        );
+        assert_eq!(output, serde_json::json!({ "a": 2, "b": 1 }));
+
+        let output = apply(
+            "
+            rename:
+                a: b
+                b: c
+            ",
+            serde_json::json!({ "a": 1, "b": 2 }),
+            serde_json::json!({}),
+        );
+        assert_eq!(output, serde_json::json!({ "b": 1, "c": 2 }));
+    }
+
+    #[test]
+    fn test_compile_rejects_invalid_template() {
+        let mapping = serde_yaml::from_str::<StageMapping>(
+            "
+            constants:
+                broken: '{{ $.input.token'
+            ",
+        )
+        .unwrap();
+
+        assert!(CompiledMapping::compile(&mapping).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::{sync::LazyLock, time::Duration};
+
+use anyhow::Result;
+use prometheus::{
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+/// Engine metrics, exposed by the agent http server at `/metrics`
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    executions: IntCounterVec,
+    execution_duration: HistogramVec,
+    executions_in_flight: IntGaugeVec,
+    stage_calls: IntCounterVec,
+    stage_call_duration: HistogramVec,
+    pool_checkout_wait: HistogramVec,
+    pool_exhausted: IntCounterVec,
+    module_fetch_duration: HistogramVec,
+    nats_reconnects: IntCounter,
+    logs_dropped: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        Self {
+            executions: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("deadlift_executions_total", "Workflow executions by status"),
+                    &["workflow", "status"],
+                ),
+            ),
+            execution_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "deadlift_execution_duration_seconds",
+                        "Workflow execution duration",
+                    ),
+                    &["workflow"],
+                ),
+            ),
+            executions_in_flight: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new(
+                        "deadlift_executions_in_flight",
+                        "Workflow executions currently running",
+                    ),
+                    &["workflow"],
+                ),
+            ),
+            stage_calls: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "deadlift_stage_calls_total",
+                        "Plugin calls by stage and status",
+                    ),
+                    &["workflow", "stage", "status"],
+                ),
+            ),
+            stage_call_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "deadlift_stage_call_duration_seconds",
+                        "Plugin call latency",
+                    ),
+                    &["workflow", "stage"],
+                ),
+            ),
+            pool_checkout_wait: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "deadlift_pool_checkout_wait_seconds",
+                        "Time spent waiting for a plugin instance",
+                    ),
+                    &["workflow", "stage"],
+                ),
+            ),
+            pool_exhausted: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "deadlift_pool_exhausted_total",
+                        "Plugin checkouts that timed out because every instance was busy",
+                    ),
+                    &["workflow", "stage"],
+                ),
+            ),
+            module_fetch_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "deadlift_module_fetch_duration_seconds",
+                        "Time spent fetching stage module bytes, from the cache or their source",
+                    ),
+                    &["workflow", "stage"],
+                ),
+            ),
+            nats_reconnects: register(
+                &registry,
+                IntCounter::new("deadlift_nats_reconnects_total", "NATS reconnections"),
+            ),
+            logs_dropped: register(
+                &registry,
+                IntCounter::new(
+                    "deadlift_logs_dropped_total",
+                    "Logs dropped because too many were waiting to be published",
+                ),
+            ),
+            registry,
+        }
+    }
+
+    /// Prometheus text exposition of every metric
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    /// Counts an execution as in flight until the guard is dropped
+    pub(crate) fn execution_started(&self, workflow: &str) -> InFlightGuard {
+        let gauge = self.executions_in_flight.with_label_values(&[workflow]);
+        gauge.inc();
+
+        InFlightGuard(gauge)
+    }
+
+    pub(crate) fn execution_finished(&self, workflow: &str, succeeded: bool, duration: Duration) {
+        self.executions
+            .with_label_values(&[workflow, status(succeeded)])
+            .inc();
+        self.execution_duration
+            .with_label_values(&[workflow])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn stage_called(
+        &self,
+        workflow: &str,
+        stage: &str,
+        succeeded: bool,
+        duration: Duration,
+    ) {
+        self.stage_calls
+            .with_label_values(&[workflow, stage, status(succeeded)])
+            .inc();
+        self.stage_call_duration
+            .with_label_values(&[workflow, stage])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn pool_checked_out(
+        &self,
+        workflow: &str,
+        stage: &str,
+        exhausted: bool,
+        wait: Duration,
+    ) {
+        self.pool_checkout_wait
+            .with_label_values(&[workflow, stage])
+            .observe(wait.as_secs_f64());
+
+        if exhausted {
+            self.pool_exhausted
+                .with_label_values(&[workflow, stage])
+                .inc();
+        }
+    }
+
+    pub(crate) fn module_fetched(&self, workflow: &str, stage: &str, duration: Duration) {
+        self.module_fetch_duration
+            .with_label_values(&[workflow, stage])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn nats_reconnected(&self) {
+        self.nats_reconnects.inc();
+    }
+
+    pub(crate) fn log_dropped(&self) {
+        self.logs_dropped.inc();
+    }
+
+    pub(crate) fn executions_in_flight(&self, workflow: &str) -> i64 {
+        self.executions_in_flight
+            .with_label_values(&[workflow])
+            .get()
+    }
+
+    pub(crate) fn executions_finished(&self, workflow: &str, succeeded: bool) -> u64 {
+        self.executions
+            .with_label_values(&[workflow, status(succeeded)])
+            .get()
+    }
+}
+
+pub(crate) struct InFlightGuard(IntGauge);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+fn register<T: Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: prometheus::Result<T>,
+) -> T {
+    let metric = metric.expect("metric options are valid");
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric is registered once");
+
+    metric
+}
+
+fn status(succeeded: bool) -> &'static str {
+    if succeeded {
+        "succeeded"
+    } else {
+        "failed"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_metrics() {
+        let metrics = Metrics::new();
+
+        {
+            let _in_flight = metrics.execution_started("math");
+            metrics.execution_finished("math", true, Duration::from_millis(5));
+        }
+
+        let encoded = metrics.encode().unwrap();
+
+        assert!(
+            encoded.contains(r#"deadlift_executions_total{status="succeeded",workflow="math"} 1"#)
+        );
+        assert!(encoded.contains(r#"deadlift_executions_in_flight{workflow="math"} 0"#));
+    }
+}
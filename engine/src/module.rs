@@ -0,0 +1,233 @@
+use std::{future::Future, time::Instant};
+
+use anyhow::{anyhow, Result};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use extism::Wasm;
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    cache::{sha256_hex, ModuleCache},
+    config::{ModuleSource, WorkflowConfig},
+    metrics::METRICS,
+};
+
+/// Resolves a module source to wasm bytes
+///
+/// Implement to customize how the engine fetches modules, e.g. from a private registry
+pub trait ModuleLoader {
+    fn load(&self, source: &ModuleSource) -> impl Future<Output = Result<Vec<u8>>> + Send;
+}
+
+/// Loads the wasm for every stage of the workflow, keyed by stage id
+pub async fn load_workflow_modules(
+    workflow: &WorkflowConfig,
+    loader: &impl ModuleLoader,
+) -> Result<Vec<(String, Wasm)>> {
+    let mut modules = vec![];
+    for stage in workflow.graph.node_weights() {
+        let started = Instant::now();
+        let wasm_bytes = loader
+            .load(&stage.module)
+            .await
+            .map_err(|e| anyhow!("failed to load module for stage '{}'; {e}", stage.id()))?;
+        METRICS.module_fetched(&workflow.name, stage.id(), started.elapsed());
+
+        modules.push((
+            stage.id().to_string(),
+            Wasm::Data {
+                data: wasm_bytes,
+                meta: extism::WasmMetadata {
+                    name: stage.namespace.clone(),
+                    hash: stage.hash.clone(),
+                },
+            },
+        ));
+    }
+
+    Ok(modules)
+}
+
+/// Loads every module source, caching fetched bytes on disk
+///
+/// Object sources are only available when constructed with a jetstream context
+#[derive(Clone)]
+pub struct DefaultModuleLoader {
+    js: Option<async_nats::jetstream::Context>,
+    cache: Option<ModuleCache>,
+}
+
+impl DefaultModuleLoader {
+    pub fn new(js: Option<async_nats::jetstream::Context>, cache: Option<ModuleCache>) -> Self {
+        Self { js, cache }
+    }
+
+    async fn load_object(
+        &self,
+        bucket: &str,
+        name: &str,
+        version: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        let js = self
+            .js
+            .as_ref()
+            .ok_or_else(|| anyhow!("object '{name}' requires a nats connection"))?;
+
+        let store = js.get_object_store(bucket).await?;
+        let info = store.info(name).await?;
+
+        if let Some(version) = version {
+            if info.digest.as_deref() != Some(version) {
+                return Err(anyhow!(
+                    "object '{name}' digest {:?} does not match version '{version}'",
+                    info.digest
+                ));
+            }
+        }
+
+        let sha256 = info.digest.as_deref().and_then(object_digest_to_sha256);
+        if let Some(bytes) = self.get_cached(sha256.as_deref()).await {
+            return Ok(bytes);
+        }
+
+        let mut object = store.get(name).await?;
+
+        let mut bytes = vec![];
+        object.read_to_end(&mut bytes).await?;
+
+        self.put_cached(&bytes).await;
+
+        Ok(bytes)
+    }
+
+    async fn load_url(&self, url: &str, sha256: Option<&str>) -> Result<Vec<u8>> {
+        if let Some(bytes) = self.get_cached(sha256).await {
+            return Ok(bytes);
+        }
+
+        let bytes = reqwest::get(url)
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec();
+
+        // unpinned urls may change, so they are fetched every time rather than cached
+        if let Some(sha256) = sha256 {
+            let actual = sha256_hex(&bytes);
+            if actual != sha256.to_lowercase() {
+                return Err(anyhow!(
+                    "module at '{url}' has sha256 {actual}, expected {sha256}"
+                ));
+            }
+
+            self.put_cached(&bytes).await;
+        }
+
+        Ok(bytes)
+    }
+
+    async fn get_cached(&self, sha256: Option<&str>) -> Option<Vec<u8>> {
+        match (&self.cache, sha256) {
+            (Some(cache), Some(sha256)) => cache.get(sha256).await,
+            _ => None,
+        }
+    }
+
+    async fn put_cached(&self, bytes: &[u8]) {
+        if let Some(cache) = &self.cache {
+            // caching is best effort; the module has already been fetched
+            let _ = cache.put(bytes).await;
+        }
+    }
+}
+
+impl ModuleLoader for DefaultModuleLoader {
+    async fn load(&self, source: &ModuleSource) -> Result<Vec<u8>> {
+        match source {
+            ModuleSource::Object {
+                bucket,
+                name,
+                version,
+            } => self.load_object(bucket, name, version.as_deref()).await,
+            ModuleSource::File { path } => tokio::fs::read(path)
+                .await
+                .map_err(|e| anyhow!("failed to read '{}'; {e}", path.display())),
+            ModuleSource::Url { url, sha256 } => self.load_url(url, sha256.as_deref()).await,
+            ModuleSource::Inline { base64 } => STANDARD
+                .decode(base64)
+                .map_err(|e| anyhow!("failed to decode inline module; {e}")),
+        }
+    }
+}
+
+/// Converts a nats object digest (`SHA-256=<base64url>`) to a hex sha256 hash
+fn object_digest_to_sha256(digest: &str) -> Option<String> {
+    let encoded = digest.strip_prefix("SHA-256=")?;
+    URL_SAFE_NO_PAD
+        .decode(encoded.trim_end_matches('='))
+        .ok()
+        .map(hex::encode)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::Digest;
+
+    use super::*;
+
+    fn test_cache() -> ModuleCache {
+        let dir = std::env::temp_dir().join(format!("deadlift-module-{}", uuid::Uuid::new_v4()));
+        ModuleCache::new(dir, 0)
+    }
+
+    #[tokio::test]
+    async fn test_load_sources() {
+        let cache = test_cache();
+        let loader = DefaultModuleLoader::new(None, Some(cache.clone()));
+        let bytes = b"\0asm module".to_vec();
+
+        let inline = ModuleSource::Inline {
+            base64: STANDARD.encode(&bytes),
+        };
+        assert_eq!(loader.load(&inline).await.unwrap(), bytes);
+
+        let path = cache.dir().join("module.wasm");
+        tokio::fs::create_dir_all(cache.dir()).await.unwrap();
+        tokio::fs::write(&path, &bytes).await.unwrap();
+        let file = ModuleSource::File { path: path.clone() };
+        assert_eq!(loader.load(&file).await.unwrap(), bytes);
+
+        // pinned urls are served from the cache without fetching
+        let sha256 = cache.put(&bytes).await.unwrap();
+        let url = ModuleSource::Url {
+            url: String::from("http://deadlift.invalid/module.wasm"),
+            sha256: Some(sha256.to_uppercase()),
+        };
+        assert_eq!(loader.load(&url).await.unwrap(), bytes);
+
+        let object = ModuleSource::Object {
+            bucket: String::from("wasm"),
+            name: String::from("module"),
+            version: None,
+        };
+        assert!(loader.load(&object).await.is_err());
+
+        let _ = tokio::fs::remove_dir_all(cache.dir()).await;
+    }
+
+    #[test]
+    fn test_object_digest_to_sha256() {
+        let digest = format!(
+            "SHA-256={}",
+            URL_SAFE_NO_PAD.encode(sha2::Sha256::digest(b"wasm"))
+        );
+        assert_eq!(
+            object_digest_to_sha256(&digest).as_deref(),
+            Some(sha256_hex(b"wasm").as_str())
+        );
+        assert_eq!(object_digest_to_sha256("MD5=abc"), None);
+    }
+}
@@ -1,24 +1,54 @@
 use std::{
     collections::HashMap,
+    future::Future,
     sync::{Arc, LazyLock, OnceLock, RwLock},
 };
 
 use anyhow::{anyhow, Result};
-use futures_util::StreamExt;
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use tracing::{error, info_span, Instrument, Span};
 
-use crate::config::NatsConfig;
+use crate::{
+    config::NatsConfig,
+    error::ExecutionError,
+    executor::{ExecutionContext, Executor},
+    health::Health,
+    shutdown::Shutdown,
+    subjects::Subjects,
+    telemetry::{set_remote_parent, traceparent},
+};
 
 const DEADLIFT_EXECUTIONS_QUEUE_GROUP: &str = "deadlift_executions";
 
+const MAX_CONCURRENT_EXECUTIONS: usize = 100;
+
+/// Caller supplied execution id; echoed in reply headers
+pub const EXECUTION_ID_HEADER: &str = "Deadlift-Execution-Id";
+
+/// W3C trace context; echoed in reply headers and passed through to plugins
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Replays the execution with this id from its retained inputs; the payload is ignored
+pub const REPLAY_OF_HEADER: &str = "Deadlift-Replay-Of";
+
+/// With `Deadlift-Replay-Of`, replays from this stage with its retained input
+pub const REPLAY_FROM_STAGE_HEADER: &str = "Deadlift-Replay-From-Stage";
+
+/// Rejects the execution unless the agent runs this version of the workflow
+pub const WORKFLOW_VERSION_HEADER: &str = "Deadlift-Workflow-Version";
+
 // TODO-- no pins are needed since everything is passed around
 static NATS_CLIENT: OnceLock<Arc<RwLock<async_nats::Client>>> = OnceLock::new();
 
 static WASM_MAP: LazyLock<Arc<RwLock<HashMap<String, String>>>> =
     LazyLock::new(|| Arc::new(RwLock::new(HashMap::new())));
 
-pub async fn require_nats(config: &NatsConfig) -> Result<async_nats::Client> {
+pub async fn require_nats(
+    config: &NatsConfig,
+    options: async_nats::ConnectOptions,
+) -> Result<async_nats::Client> {
     if NATS_CLIENT.get().is_none() {
-        let nc = config.connect().await?;
+        let nc = options.connect(&config.url).await?;
 
         if NATS_CLIENT.set(Arc::new(RwLock::new(nc))).is_err() {
             // log instead of return here?
@@ -40,53 +70,224 @@ pub fn get_wasm_map() -> Arc<RwLock<HashMap<String, String>>> {
     WASM_MAP.clone()
 }
 
+/// Runs requests to the workflow and stage subjects of a workflow, spread across the agents
+/// running it; see [`Subjects`]
 pub async fn start_execution_thread(
     nc: async_nats::Client,
-    pool: extism::Pool,
-) -> tokio::task::JoinHandle<()> {
-    tokio::task::spawn(async move {
-        let subscriber = nc
-            .queue_subscribe(
-                "deadlift.executions.*",
-                String::from(DEADLIFT_EXECUTIONS_QUEUE_GROUP),
-            )
-            .await
-            .unwrap();
-
-        let _ = subscriber
-            .for_each_concurrent(100, |msg| {
-                let nc = nc.clone();
-                let pool = pool.clone();
-
-                async move {
-                    let res = tokio::task::spawn_blocking(move || {
-                        match pool.get(
-                            &String::from(msg.subject.as_str()),
-                            std::time::Duration::from_millis(500),
-                        ) {
-                            Ok(Some(pool_plugin)) => pool_plugin
-                                .call::<Vec<u8>, Vec<u8>>("probe_urls", msg.payload.to_vec()),
-                            Ok(None) => {
-                                Err(extism::Error::msg("failed to resolve plugin; timed out"))
-                            }
-                            Err(e) => {
-                                Err(extism::Error::msg(format!("failed to resolve plugin; {e}")))
-                            }
-                        }
-                    })
-                    .await
-                    .unwrap();
-
-                    if let Some(reply) = msg.reply {
-                        nc.publish(
+    executor: Arc<Executor>,
+    subjects: Subjects,
+    health: Arc<Health>,
+    shutdown: Shutdown,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let workflow = executor.workflow();
+
+    let mut subscribers = vec![];
+    for subject in subjects.served(&workflow.name, workflow.version) {
+        subscribers.push(
+            nc.queue_subscribe(subject, String::from(DEADLIFT_EXECUTIONS_QUEUE_GROUP))
+                .await?,
+        );
+    }
+
+    Ok(tokio::task::spawn(async move {
+        let _running = health.execution_thread_guard(&executor.workflow().name, shutdown.clone());
+
+        serve_until_draining(subscribers, MAX_CONCURRENT_EXECUTIONS, &shutdown, |msg| {
+            let nc = nc.clone();
+            let executor = executor.clone();
+            let stage = subjects.stage_token(&msg.subject).map(String::from);
+
+            let mut context = execution_context("nats", msg.headers.as_ref());
+            let span = receive_span(&msg.subject, &mut context);
+
+            async move {
+                let res =
+                    handle_execution(&executor, context.clone(), &msg, stage.as_deref()).await;
+
+                if let Some(reply) = msg.reply {
+                    if let Err(e) = nc
+                        .publish_with_headers(
                             reply,
+                            reply_headers(&context),
                             res.unwrap_or_else(|e| e.to_string().into_bytes()).into(),
                         )
+                        .instrument(info_span!("reply"))
                         .await
-                        .unwrap();
+                    {
+                        error!("failed to reply to '{}'; {e}", msg.subject);
+                    }
+                }
+            }
+            .instrument(span)
+        })
+        .await;
+    }))
+}
+
+/// Runs `handle` for the messages of the subscribers, at most `limit` at a time, until draining
+///
+/// Once draining, the subscribers are unsubscribed right away so that queue groups route new
+/// messages to other agents; messages already received and in-flight handlers still finish.
+pub(crate) async fn serve_until_draining<F, Fut>(
+    subscribers: Vec<async_nats::Subscriber>,
+    limit: usize,
+    shutdown: &Shutdown,
+    mut handle: F,
+) where
+    F: FnMut(async_nats::Message) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut messages = futures::stream::select_all(subscribers);
+    let mut in_flight = FuturesUnordered::new();
+    let mut draining = std::pin::pin!(shutdown.draining());
+    let mut unsubscribed = false;
+
+    loop {
+        tokio::select! {
+            msg = messages.next(), if in_flight.len() < limit => match msg {
+                Some(msg) => in_flight.push(handle(msg)),
+                // only ends once unsubscribed and every received message is handled
+                None => break,
+            },
+            Some(()) = in_flight.next(), if !in_flight.is_empty() => {}
+            _ = &mut draining, if !unsubscribed => {
+                unsubscribed = true;
+                for subscriber in messages.iter_mut() {
+                    if let Err(e) = subscriber.unsubscribe().await {
+                        error!("failed to unsubscribe; {e}");
                     }
                 }
-            })
+            }
+        }
+    }
+
+    while in_flight.next().await.is_some() {}
+}
+
+/// Builds the context of an execution started by a message, keeping a caller supplied execution
+/// id and trace context; the executor rejects ids that aren't valid tokens
+pub(crate) fn execution_context(
+    trigger: impl Into<String>,
+    headers: Option<&async_nats::HeaderMap>,
+) -> ExecutionContext {
+    let mut context = ExecutionContext::new(trigger);
+
+    if let Some(id) = get_header(headers, EXECUTION_ID_HEADER) {
+        context.id = id.to_string();
+    }
+    context.traceparent = get_header(headers, TRACEPARENT_HEADER).map(String::from);
+
+    context
+}
+
+/// Span covering a received message, continuing the caller's trace; the context gets the
+/// span's trace context when the caller did not send one
+pub(crate) fn receive_span(subject: &str, context: &mut ExecutionContext) -> Span {
+    let span = info_span!("receive", subject, execution_id = %context.id);
+    set_remote_parent(&span, context.traceparent.as_deref());
+
+    if context.traceparent.is_none() {
+        context.traceparent = traceparent(&span);
+    }
+
+    span
+}
+
+pub(crate) fn reply_headers(context: &ExecutionContext) -> async_nats::HeaderMap {
+    let mut headers = async_nats::HeaderMap::new();
+    headers.insert(EXECUTION_ID_HEADER, context.id.as_str());
+
+    if let Some(traceparent) = &context.traceparent {
+        headers.insert(TRACEPARENT_HEADER, traceparent.as_str());
+    }
+
+    headers
+}
+
+/// Runs an execution request, checking the requested workflow version and replaying when asked;
+/// with a stage, only that stage runs
+pub(crate) async fn handle_execution(
+    executor: &Arc<Executor>,
+    context: ExecutionContext,
+    msg: &async_nats::Message,
+    stage: Option<&str>,
+) -> Result<Vec<u8>> {
+    let headers = msg.headers.as_ref();
+
+    if let Some(version) = get_header(headers, WORKFLOW_VERSION_HEADER) {
+        let version = version.parse::<u32>()?;
+
+        if version != executor.workflow().version {
+            return Err(ExecutionError::VersionMismatch {
+                workflow: executor.workflow().name.clone(),
+                running: executor.workflow().version,
+                requested: version,
+            }
+            .into());
+        }
+    }
+
+    if let Some(stage) = stage {
+        if get_header(headers, REPLAY_OF_HEADER).is_some() {
+            return Err(anyhow!(
+                "single stages cannot be replayed; replay the workflow from the stage instead"
+            ));
+        }
+
+        return executor
+            .execute_stage_async(context, stage, msg.payload.to_vec())
             .await;
-    })
+    }
+
+    match get_header(headers, REPLAY_OF_HEADER) {
+        Some(replay_of) => {
+            let context = ExecutionContext {
+                id: context.id,
+                traceparent: context.traceparent,
+                ..ExecutionContext::replay(replay_of)
+            };
+
+            executor
+                .replay(context, get_header(headers, REPLAY_FROM_STAGE_HEADER))
+                .await
+        }
+        None => executor.execute_async(context, msg.payload.to_vec()).await,
+    }
+}
+
+fn get_header<'a>(headers: Option<&'a async_nats::HeaderMap>, name: &str) -> Option<&'a str> {
+    headers
+        .and_then(|headers| headers.get(name))
+        .map(|value| value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_execution_context() {
+        let mut headers = async_nats::HeaderMap::new();
+        headers.insert(EXECUTION_ID_HEADER, "order_42");
+        headers.insert(
+            TRACEPARENT_HEADER,
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        );
+
+        let context = execution_context("nats", Some(&headers));
+        assert_eq!(context.id, "order_42");
+        assert_eq!(context.trigger, "nats");
+        assert_eq!(
+            get_header(Some(&reply_headers(&context)), EXECUTION_ID_HEADER),
+            Some("order_42")
+        );
+        assert_eq!(
+            get_header(Some(&reply_headers(&context)), TRACEPARENT_HEADER),
+            context.traceparent.as_deref()
+        );
+
+        let context = execution_context("nats", None);
+        assert!(crate::executor::validate_execution_id(&context.id).is_ok());
+        assert!(context.traceparent.is_none());
+    }
 }
@@ -0,0 +1,93 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{anyhow, Result};
+
+/// Workflow placement constraint, matched against agent labels
+///
+/// `key=value` requires the label to have the value, `key!=value` requires it not to, and a bare
+/// `key` requires the label to be set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Constraint {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+}
+
+impl FromStr for Constraint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+
+        let constraint = if let Some((key, value)) = s.split_once("!=") {
+            Constraint::NotEquals(key.trim().to_string(), value.trim().to_string())
+        } else if let Some((key, value)) = s.split_once('=') {
+            Constraint::Equals(key.trim().to_string(), value.trim().to_string())
+        } else {
+            Constraint::Exists(s.to_string())
+        };
+
+        match &constraint {
+            Constraint::Equals(key, _)
+            | Constraint::NotEquals(key, _)
+            | Constraint::Exists(key)
+                if key.is_empty() =>
+            {
+                Err(anyhow!(
+                    "invalid placement constraint '{s}'; expected a label key"
+                ))
+            }
+            _ => Ok(constraint),
+        }
+    }
+}
+
+impl Constraint {
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        match self {
+            Constraint::Equals(key, value) => labels.get(key) == Some(value),
+            Constraint::NotEquals(key, value) => labels.get(key) != Some(value),
+            Constraint::Exists(key) => labels.contains_key(key),
+        }
+    }
+}
+
+/// Whether an agent with `labels` satisfies every constraint of a workflow's `placement`
+pub fn is_placed(placement: &[String], labels: &HashMap<String, String>) -> Result<bool> {
+    for constraint in placement {
+        if !constraint.parse::<Constraint>()?.matches(labels) {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_placement() {
+        let labels = HashMap::from([
+            (String::from("region"), String::from("eu")),
+            (String::from("site"), String::from("store-42")),
+            (String::from("postgres"), String::from("true")),
+        ]);
+
+        let placement = |constraints: &[&str]| {
+            let constraints = constraints
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>();
+            is_placed(&constraints, &labels).unwrap()
+        };
+
+        assert!(placement(&[]));
+        assert!(placement(&["region=eu", "site = store-42", "postgres"]));
+        assert!(placement(&["region!=us"]));
+        assert!(!placement(&["region=us"]));
+        assert!(!placement(&["gpu"]));
+        assert!(is_placed(&[String::from("=eu")], &labels).is_err());
+    }
+}
@@ -1,38 +1,41 @@
-use std::sync::OnceLock;
-
-use anyhow::{anyhow, Result};
 use extism::*;
 
-use crate::config::PluginConfig;
+use crate::{cache::ModuleCache, config::PluginConfig, host::HostFunctions};
 
-static PLUGIN_POOL: OnceLock<extism::Pool> = OnceLock::new();
+const MAX_POOL_INSTANCES: usize = 100;
 
-pub async fn require_plugin_pool(
-    wasm: Vec<Wasm>,
+/// Creates a pool with one plugin builder per module, keyed by stage id
+///
+/// Each plugin only holds its stage's module, so modules are not linked across stages.
+pub fn new_plugin_pool(
+    modules: Vec<(String, Wasm)>,
     plugin_config: &PluginConfig,
-) -> Result<extism::Pool> {
-    if PLUGIN_POOL.get().is_none() {
-        let mut manifest =
-            Manifest::new(wasm).with_allowed_hosts(plugin_config.allowed_hosts.clone().into_iter());
+    host_functions: &HostFunctions,
+) -> extism::Pool {
+    let pool = extism::Pool::new(MAX_POOL_INSTANCES);
+
+    // compiled modules are cached on disk so that restarts skip recompilation
+    let cache_config_path = ModuleCache::from_plugin_config(plugin_config)
+        .and_then(|cache| cache.compilation_config().ok());
+
+    for (key, wasm) in modules {
+        let mut manifest = Manifest::new([wasm])
+            .with_allowed_hosts(plugin_config.allowed_hosts.clone().into_iter());
 
         if let Some(extism_config) = &plugin_config.extism_config {
             manifest = manifest.with_config(extism_config.iter());
         }
 
-        let plugin_builder = PluginBuilder::new(manifest).with_wasi(plugin_config.wasi);
+        let mut plugin_builder = PluginBuilder::new(manifest)
+            .with_wasi(plugin_config.wasi)
+            .with_functions(host_functions.for_stage(&key));
 
-        let pool = extism::Pool::new(100);
-        pool.add_builder("probe_urls".to_string(), plugin_builder);
-
-        if PLUGIN_POOL.set(pool).is_err() {
-            // log instead of return here?
-            return Err(anyhow!("failed to initialize plugin"));
+        if let Some(cache_config_path) = &cache_config_path {
+            plugin_builder = plugin_builder.with_cache_config(cache_config_path);
         }
-    }
 
-    if let Some(guard) = PLUGIN_POOL.get() {
-        return Ok(guard.clone());
+        pool.add_builder(key, plugin_builder);
     }
 
-    Err(anyhow!("failed to get plugin"))
+    pool
 }
@@ -0,0 +1,238 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_nats::jetstream::kv;
+use chrono::{DateTime, Utc};
+use extism::Wasm;
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::error;
+
+use crate::{
+    cache::sha256_hex,
+    config::{AgentConfig, WorkflowConfig},
+    health::Health,
+    metrics::METRICS,
+    shutdown::Shutdown,
+    utils::{get_or_create_key_value, to_name_token},
+    workflows::Workflows,
+};
+
+pub const AGENT_BUCKET_NAME: &str = "deadlift_agents";
+
+/// Heartbeat of a running agent, kept in the `deadlift_agents` bucket under its id
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AgentRecord {
+    pub id: String,
+    pub hostname: String,
+    pub version: String,
+    pub labels: HashMap<String, String>,
+    pub workflows: Vec<WorkflowRecord>,
+    pub load: AgentLoad,
+    pub ready: bool,
+    pub started_at: DateTime<Utc>,
+    pub heartbeat_at: DateTime<Utc>,
+    pub heartbeat_interval_secs: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkflowRecord {
+    pub name: String,
+    pub version: u32,
+    pub modules: Vec<ModuleRecord>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModuleRecord {
+    pub stage: String,
+    pub sha256: String,
+}
+
+/// Executions of the agent's workflows since it started
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AgentLoad {
+    pub executions_in_flight: i64,
+    pub executions_succeeded: u64,
+    pub executions_failed: u64,
+}
+
+impl AgentRecord {
+    pub fn new(config: &AgentConfig, workflows: Vec<WorkflowRecord>) -> Self {
+        let now = Utc::now();
+
+        Self {
+            id: config
+                .id
+                .as_deref()
+                .map(to_name_token)
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            hostname: hostname(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            labels: config.labels.clone(),
+            workflows,
+            load: AgentLoad::default(),
+            ready: false,
+            started_at: now,
+            heartbeat_at: now,
+            heartbeat_interval_secs: config.heartbeat_interval_secs,
+        }
+    }
+
+    /// Whether the agent missed more than two heartbeats
+    pub fn is_stale(&self) -> bool {
+        let interval = chrono::Duration::seconds(self.heartbeat_interval_secs as i64);
+        Utc::now() - self.heartbeat_at > interval * 3
+    }
+}
+
+impl WorkflowRecord {
+    /// Describes a workflow and the sha256 of each stage module
+    pub fn new(workflow: &WorkflowConfig, modules: &[(String, Wasm)]) -> Self {
+        Self {
+            name: workflow.name.clone(),
+            version: workflow.version,
+            modules: modules
+                .iter()
+                .filter_map(|(stage, wasm)| match wasm {
+                    Wasm::Data { data, .. } => Some(ModuleRecord {
+                        stage: stage.clone(),
+                        sha256: sha256_hex(data),
+                    }),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Puts a heartbeat with the agent's current workflows every `heartbeat_interval_secs`, and
+/// removes it once draining
+pub async fn start_heartbeat_thread(
+    js: async_nats::jetstream::Context,
+    mut record: AgentRecord,
+    workflows: Arc<Workflows>,
+    health: Arc<Health>,
+    shutdown: Shutdown,
+) -> Result<JoinHandle<()>> {
+    let interval = Duration::from_secs(record.heartbeat_interval_secs);
+
+    let kv = get_or_create_key_value(
+        &js,
+        kv::Config {
+            bucket: AGENT_BUCKET_NAME.to_string(),
+            history: 1,
+            max_age: interval * 3,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    Ok(tokio::task::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = shutdown.draining() => break,
+            }
+
+            record.heartbeat_at = Utc::now();
+            record.workflows = workflows.records();
+            record.ready = health.report().ready;
+            record.load = AgentLoad::default();
+            for workflow in &record.workflows {
+                record.load.executions_in_flight += METRICS.executions_in_flight(&workflow.name);
+                record.load.executions_succeeded +=
+                    METRICS.executions_finished(&workflow.name, true);
+                record.load.executions_failed += METRICS.executions_finished(&workflow.name, false);
+            }
+
+            let payload = match serde_json::to_vec(&record) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!("failed to serialize heartbeat; {e}");
+                    continue;
+                }
+            };
+
+            if let Err(e) = kv.put(&record.id, payload.into()).await {
+                error!("failed to put heartbeat; {e}");
+            }
+        }
+
+        if let Err(e) = kv.delete(&record.id).await {
+            error!("failed to remove heartbeat; {e}");
+        }
+    }))
+}
+
+/// Reads agent heartbeats
+pub struct AgentRegistry {
+    kv: kv::Store,
+}
+
+impl AgentRegistry {
+    pub async fn open(js: &async_nats::jetstream::Context) -> Result<Self> {
+        let kv = js
+            .get_key_value(AGENT_BUCKET_NAME)
+            .await
+            .map_err(|e| anyhow!("failed to open agent registry; {e}"))?;
+
+        Ok(Self { kv })
+    }
+
+    /// Lists agents that sent a heartbeat recently, by id
+    pub async fn list(&self) -> Result<Vec<AgentRecord>> {
+        let keys = self.kv.keys().await?.try_collect::<Vec<_>>().await?;
+
+        let mut records = vec![];
+        for key in keys {
+            if let Some(value) = self.kv.get(&key).await? {
+                records.push(serde_json::from_slice::<AgentRecord>(&value)?);
+            }
+        }
+
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(records)
+    }
+
+    pub async fn get(&self, id: &str) -> Result<AgentRecord> {
+        let value = self
+            .kv
+            .get(to_name_token(id))
+            .await?
+            .ok_or_else(|| anyhow!("agent '{id}' not found"))?;
+
+        Ok(serde_json::from_slice(&value)?)
+    }
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent_record() {
+        let config = AgentConfig {
+            id: Some(String::from("berlin edge 1")),
+            ..Default::default()
+        };
+        let mut record = AgentRecord::new(&config, vec![]);
+        assert_eq!(record.id, "berlin_edge_1");
+        assert!(!record.is_stale());
+
+        record.heartbeat_at -= chrono::Duration::seconds(config.heartbeat_interval_secs as i64 * 4);
+        assert!(record.is_stale());
+    }
+}
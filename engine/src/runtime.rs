@@ -0,0 +1,209 @@
+use std::{future::Future, time::Duration};
+
+use anyhow::{anyhow, Error, Result};
+use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+use tracing::{error, info, warn};
+
+use crate::shutdown::Shutdown;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A task that ran for this long is considered healthy again, resetting its backoff
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Consecutive failures after which a task is reported as fatal
+const MAX_RESTARTS: u32 = 5;
+
+/// Runs an agent until `SIGINT` or `SIGTERM`, then shuts it down gracefully
+///
+/// Returns an error if the agent fails to start, or if a supervised task keeps failing; the
+/// agent is still drained first.
+pub async fn run_agent(config_bytes: Vec<u8>) -> Result<()> {
+    let mut handles = crate::run(config_bytes).await?;
+
+    let res = tokio::select! {
+        res = shutdown_signal() => res,
+        e = handles.fatal() => Err(e),
+    };
+
+    handles.shutdown().await;
+
+    res
+}
+
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    info!("shutdown requested");
+
+    Ok(())
+}
+
+/// Fatal errors of supervised tasks
+pub(crate) type FatalSender = mpsc::UnboundedSender<Error>;
+
+/// Starts a task with `start`, then restarts it with backoff when it panics, ends before the
+/// engine is draining, or fails to start again
+///
+/// Errors of the first start are returned, so that they fail `run`. Aborting the returned handle
+/// aborts the current task.
+pub(crate) async fn supervise<F, Fut>(
+    name: impl Into<String>,
+    start: F,
+    shutdown: Shutdown,
+    fatal: FatalSender,
+) -> Result<JoinHandle<()>>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<JoinHandle<()>>> + Send + 'static,
+{
+    let name = name.into();
+    let first = start().await?;
+
+    Ok(tokio::task::spawn(async move {
+        let mut task = Some(first);
+        let mut backoff = INITIAL_BACKOFF;
+        let mut failures = 0;
+
+        loop {
+            let started_at = Instant::now();
+
+            let failure = match task.take() {
+                Some(handle) => {
+                    let mut handle = AbortOnDrop(handle);
+
+                    match (&mut handle.0).await {
+                        Ok(()) if shutdown.is_draining() => return,
+                        Ok(()) => anyhow!("task '{name}' ended"),
+                        Err(e) if e.is_panic() => anyhow!("task '{name}' panicked"),
+                        Err(_) => return,
+                    }
+                }
+                None => match start().await {
+                    Ok(handle) => {
+                        task = Some(handle);
+                        continue;
+                    }
+                    Err(e) => anyhow!("task '{name}' failed to start; {e}"),
+                },
+            };
+
+            if shutdown.is_draining() {
+                return;
+            }
+
+            if started_at.elapsed() >= STABLE_AFTER {
+                backoff = INITIAL_BACKOFF;
+                failures = 0;
+            }
+
+            failures += 1;
+            if failures > MAX_RESTARTS {
+                error!("{failure}; giving up after {MAX_RESTARTS} restarts");
+                let _ = fatal.send(failure);
+                return;
+            }
+
+            warn!("{failure}; restarting in {}s", backoff.as_secs());
+
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = shutdown.draining() => return,
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }))
+}
+
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::shutdown::shutdown_channel;
+
+    /// Starts a task that panics on the starts `panics` returns true for, and otherwise runs
+    /// until draining
+    fn panicking_start(
+        starts: Arc<AtomicUsize>,
+        shutdown: Shutdown,
+        panics: fn(usize) -> bool,
+    ) -> impl Fn() -> futures::future::Ready<Result<JoinHandle<()>>> {
+        move || {
+            let start = starts.fetch_add(1, Ordering::SeqCst);
+            let draining = shutdown.draining();
+
+            futures::future::ready(Ok(tokio::task::spawn(async move {
+                if panics(start) {
+                    panic!("start {start} fails");
+                }
+                draining.await;
+            })))
+        }
+    }
+
+    // time is paused, so the backoff sleeps advance the clock instead of waiting
+    #[tokio::test(start_paused = true)]
+    async fn test_supervise_restarts_panicked_task() {
+        let (controller, shutdown) = shutdown_channel();
+        let (fatal_tx, mut fatal_rx) = mpsc::unbounded_channel();
+        let starts = Arc::new(AtomicUsize::new(0));
+
+        let start = panicking_start(starts.clone(), shutdown.clone(), |start| start == 0);
+
+        let handle = supervise("test", start, shutdown, fatal_tx).await.unwrap();
+
+        tokio::time::sleep(INITIAL_BACKOFF + Duration::from_millis(500)).await;
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+
+        controller.drain();
+        handle.await.unwrap();
+        assert!(fatal_rx.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_supervise_gives_up() {
+        let (_controller, shutdown) = shutdown_channel();
+        let (fatal_tx, mut fatal_rx) = mpsc::unbounded_channel();
+        let starts = Arc::new(AtomicUsize::new(0));
+
+        let start = panicking_start(starts.clone(), shutdown.clone(), |_| true);
+        let handle = supervise("test", start, shutdown, fatal_tx).await.unwrap();
+
+        let started = Instant::now();
+        let fatal = fatal_rx.recv().await.unwrap();
+        handle.await.unwrap();
+
+        assert_eq!(fatal.to_string(), "task 'test' panicked");
+        assert_eq!(starts.load(Ordering::SeqCst), MAX_RESTARTS as usize + 1);
+
+        // 1 + 2 + 4 + 8 + 16 seconds of backoff
+        assert_eq!(
+            started.elapsed().as_secs(),
+            (0..MAX_RESTARTS).map(|n| 1 << n).sum::<u64>()
+        );
+    }
+}
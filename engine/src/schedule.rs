@@ -0,0 +1,311 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_nats::jetstream::kv::{self, CreateErrorKind};
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{error, warn};
+
+use crate::{
+    config::{CatchUpPolicy, ScheduleConfig},
+    executor::{ExecutionContext, Executor},
+    shutdown::Shutdown,
+    utils::{get_or_create_key_value, to_name_token},
+};
+
+pub const SCHEDULE_BUCKET_NAME: &str = "deadlift_schedules";
+
+/// Tick locks expire after this long; catch-up cannot look further back
+const SCHEDULE_BUCKET_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const MAX_CATCH_UP_TICKS: usize = 100;
+
+pub async fn start_scheduler_thread(
+    js: async_nats::jetstream::Context,
+    executor: Arc<Executor>,
+    shutdown: Shutdown,
+) -> Result<JoinHandle<()>> {
+    let schedules = executor
+        .workflow()
+        .schedules
+        .iter()
+        .map(|config| Schedule::new(&executor.workflow().name, config))
+        .collect::<Result<Vec<_>>>()?;
+
+    let kv = get_or_create_key_value(
+        &js,
+        kv::Config {
+            bucket: SCHEDULE_BUCKET_NAME.to_string(),
+            history: 1,
+            max_age: SCHEDULE_BUCKET_MAX_AGE,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    Ok(tokio::task::spawn(async move {
+        futures::future::join_all(
+            schedules
+                .into_iter()
+                .map(|schedule| schedule.run(kv.clone(), executor.clone(), shutdown.clone())),
+        )
+        .await;
+
+        // schedules without upcoming ticks end early; the thread is only done once draining
+        shutdown.draining().await;
+    }))
+}
+
+struct Schedule {
+    name: String,
+    key_prefix: String,
+    cadence: Cadence,
+    catch_up: CatchUpPolicy,
+    input: Vec<u8>,
+}
+
+impl Schedule {
+    fn new(workflow_name: &str, config: &ScheduleConfig) -> Result<Self> {
+        let input = match &config.input {
+            Some(serde_json::Value::String(s)) => s.as_bytes().to_vec(),
+            Some(value) => serde_json::to_vec(value)?,
+            None => vec![],
+        };
+
+        Ok(Self {
+            name: config.name.clone(),
+            key_prefix: format!(
+                "{}.{}",
+                to_name_token(workflow_name),
+                to_name_token(&config.name)
+            ),
+            cadence: Cadence::from_config(config)
+                .map_err(|e| anyhow!("schedule '{}'; {e}", config.name))?,
+            catch_up: config.catch_up,
+            input,
+        })
+    }
+
+    async fn run(self, kv: kv::Store, executor: Arc<Executor>, shutdown: Shutdown) {
+        let mut running = JoinSet::new();
+        let mut after = self.last_tick(&kv).await.unwrap_or_else(Utc::now);
+
+        loop {
+            // ticks passed without being fired, whether no agent was running the schedule or
+            // locking the previous tick was slow, are handled by the catch-up policy
+            let now = Utc::now();
+            for tick in self.catch_up_ticks(self.cadence.ticks_between(after, now)) {
+                if shutdown.is_draining() {
+                    break;
+                }
+                self.fire(tick, &kv, &executor, &mut running).await;
+            }
+            after = after.max(now);
+
+            let Some(tick) = self.cadence.next_after(after) else {
+                warn!("schedule '{}' has no upcoming ticks", self.name);
+                break;
+            };
+
+            let delay = (tick - Utc::now()).to_std().unwrap_or_default();
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.draining() => break,
+            }
+
+            self.fire(tick, &kv, &executor, &mut running).await;
+            after = tick;
+
+            while running.try_join_next().is_some() {}
+        }
+
+        // in-flight executions finish within the shutdown grace period
+        while running.join_next().await.is_some() {}
+    }
+
+    fn catch_up_ticks(&self, mut missed_ticks: Vec<DateTime<Utc>>) -> Vec<DateTime<Utc>> {
+        match self.catch_up {
+            CatchUpPolicy::Skip => vec![],
+            CatchUpPolicy::Latest => missed_ticks.pop().into_iter().collect(),
+            CatchUpPolicy::All => missed_ticks,
+        }
+    }
+
+    /// Starts the workflow for `tick` if no other agent has locked it; executions run in
+    /// `running` so a slow execution doesn't hold up the next tick
+    async fn fire(
+        &self,
+        tick: DateTime<Utc>,
+        kv: &kv::Store,
+        executor: &Arc<Executor>,
+        running: &mut JoinSet<()>,
+    ) {
+        let lock_key = format!("{}.{}", self.key_prefix, tick.timestamp());
+
+        match kv
+            .create(&lock_key, std::process::id().to_string().into())
+            .await
+        {
+            Ok(_) => {}
+            Err(e) if e.kind() == CreateErrorKind::AlreadyExists => return,
+            Err(e) => {
+                error!("schedule '{}' failed to lock tick {tick}; {e}", self.name);
+                return;
+            }
+        }
+
+        // the lock claims the tick, so it is recorded before executing and stays in tick order
+        // when executions overlap
+        let last_key = format!("{}.last", self.key_prefix);
+        if let Err(e) = kv.put(&last_key, tick.timestamp().to_string().into()).await {
+            error!("schedule '{}' failed to record tick {tick}; {e}", self.name);
+        }
+
+        let name = self.name.clone();
+        let input = self.input.clone();
+        let executor = executor.clone();
+        running.spawn(async move {
+            if let Err(e) = executor
+                .execute_async(ExecutionContext::new(format!("schedule:{name}")), input)
+                .await
+            {
+                error!("schedule '{name}' tick {tick} failed; {e}");
+            }
+        });
+    }
+
+    async fn last_tick(&self, kv: &kv::Store) -> Option<DateTime<Utc>> {
+        let value = kv.get(format!("{}.last", self.key_prefix)).await.ok()??;
+        let timestamp = String::from_utf8_lossy(&value).parse::<i64>().ok()?;
+
+        Utc.timestamp_opt(timestamp, 0).single()
+    }
+}
+
+enum Cadence {
+    Cron {
+        schedule: Box<cron::Schedule>,
+        timezone: Tz,
+    },
+    Interval {
+        secs: i64,
+    },
+}
+
+impl Cadence {
+    fn from_config(config: &ScheduleConfig) -> Result<Self> {
+        match (&config.cron, config.interval_secs) {
+            (Some(expression), None) => Ok(Cadence::Cron {
+                schedule: Box::new(
+                    cron::Schedule::from_str(expression)
+                        .map_err(|e| anyhow!("invalid cron expression '{expression}'; {e}"))?,
+                ),
+                timezone: match &config.timezone {
+                    Some(timezone) => timezone
+                        .parse::<Tz>()
+                        .map_err(|e| anyhow!("invalid timezone '{timezone}'; {e}"))?,
+                    None => Tz::UTC,
+                },
+            }),
+            (None, Some(secs)) if secs > 0 => Ok(Cadence::Interval { secs: secs as i64 }),
+            (None, Some(_)) => Err(anyhow!("interval_secs must be greater than 0")),
+            _ => Err(anyhow!("exactly one of cron or interval_secs is required")),
+        }
+    }
+
+    /// First tick strictly after `after`
+    fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Cadence::Cron { schedule, timezone } => schedule
+                .after(&after.with_timezone(timezone))
+                .next()
+                .map(|tick| tick.with_timezone(&Utc)),
+            Cadence::Interval { secs } => {
+                let next = (after.timestamp().div_euclid(*secs) + 1) * secs;
+                Utc.timestamp_opt(next, 0).single()
+            }
+        }
+    }
+
+    /// Ticks after `start` up to and including `end`, keeping the most recent
+    fn ticks_between(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        let mut ticks = std::collections::VecDeque::new();
+
+        let mut after = start;
+        while let Some(tick) = self.next_after(after).filter(|tick| *tick <= end) {
+            if ticks.len() == MAX_CATCH_UP_TICKS {
+                ticks.pop_front();
+            }
+            ticks.push_back(tick);
+            after = tick;
+        }
+
+        ticks.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cadence(config: &str) -> Cadence {
+        Cadence::from_config(&serde_yaml::from_str::<ScheduleConfig>(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_cadence_ticks() {
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 10, 2, 30).unwrap();
+
+        let interval = cadence("{ name: poll, interval_secs: 300 }");
+        assert_eq!(
+            interval.next_after(start),
+            Some(Utc.with_ymd_and_hms(2024, 3, 1, 10, 5, 0).unwrap())
+        );
+        assert_eq!(
+            interval
+                .ticks_between(start, Utc.with_ymd_and_hms(2024, 3, 1, 10, 20, 0).unwrap())
+                .len(),
+            4
+        );
+
+        let cron = cadence("{ name: daily, cron: '0 0 9 * * *', timezone: Europe/Berlin }");
+        assert_eq!(
+            cron.next_after(start),
+            Some(Utc.with_ymd_and_hms(2024, 3, 2, 8, 0, 0).unwrap())
+        );
+
+        let invalid = serde_yaml::from_str::<ScheduleConfig>(
+            "{ name: both, cron: '0 0 9 * * *', interval_secs: 60 }",
+        )
+        .unwrap();
+        assert!(Cadence::from_config(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_catch_up_ticks() {
+        let schedule = |catch_up: &str| {
+            Schedule::new(
+                "workflow",
+                &serde_yaml::from_str::<ScheduleConfig>(&format!(
+                    "{{ name: poll, interval_secs: 60, catch_up: {catch_up} }}"
+                ))
+                .unwrap(),
+            )
+            .unwrap()
+        };
+
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 10, 0, 0).unwrap();
+        let missed = schedule("skip")
+            .cadence
+            .ticks_between(start, start + chrono::Duration::seconds(180));
+        assert_eq!(missed.len(), 3);
+
+        assert!(schedule("skip").catch_up_ticks(missed.clone()).is_empty());
+        assert_eq!(
+            schedule("latest").catch_up_ticks(missed.clone()),
+            [start + chrono::Duration::seconds(180)]
+        );
+        assert_eq!(schedule("all").catch_up_ticks(missed.clone()), missed);
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::{anyhow, Result};
+use jsonschema::JSONSchema;
+use serde_json::Value;
+
+use crate::error::{ExecutionError, PayloadKind};
+
+/// JSON schema compiled once when the workflow is loaded
+pub struct CompiledSchema(JSONSchema);
+
+impl CompiledSchema {
+    pub fn compile(schema: &Value) -> Result<Self> {
+        JSONSchema::compile(schema)
+            .map(Self)
+            .map_err(|e| anyhow!("invalid json schema; {e}"))
+    }
+
+    pub fn compile_opt(schema: Option<&Value>) -> Result<Option<Self>> {
+        schema.map(Self::compile).transpose()
+    }
+
+    /// Validates a raw payload, reporting the first failing JSON pointer
+    pub fn validate(
+        &self,
+        stage: Option<&str>,
+        payload_kind: PayloadKind,
+        payload: &[u8],
+    ) -> Result<(), ExecutionError> {
+        let violation = |pointer: String, message: String| ExecutionError::SchemaViolation {
+            stage: stage.map(String::from),
+            payload: payload_kind,
+            pointer,
+            message,
+        };
+
+        let instance = serde_json::from_slice::<Value>(payload)
+            .map_err(|e| violation(String::new(), format!("payload is not valid json; {e}")))?;
+
+        if let Err(mut errors) = self.0.validate(&instance) {
+            if let Some(error) = errors.next() {
+                return Err(violation(
+                    error.instance_path.to_string(),
+                    error.to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_reports_failing_pointer() {
+        let schema = CompiledSchema::compile(&serde_json::json!({
+            "type": "object",
+            "properties": {
+                "items": {
+                    "type": "array",
+                    "items": { "type": "integer" }
+                }
+            }
+        }))
+        .unwrap();
+
+        assert!(schema
+            .validate(Some("sum"), PayloadKind::Input, br#"{"items":[1,2]}"#)
+            .is_ok());
+
+        let err = schema
+            .validate(Some("sum"), PayloadKind::Input, br#"{"items":[1,"two"]}"#)
+            .unwrap_err();
+
+        assert_eq!(err.code(), "schema_violation");
+        assert!(matches!(
+            &err,
+            ExecutionError::SchemaViolation { stage: Some(stage), pointer, .. }
+                if stage == "sum" && pointer == "/items/1"
+        ));
+
+        let err = schema
+            .validate(None, PayloadKind::Input, b"not json")
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("schema_violation: workflow input at ''"));
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use async_nats::service::{self, ServiceExt, NATS_SERVICE_ERROR, NATS_SERVICE_ERROR_CODE};
+use futures_util::StreamExt;
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{error, Instrument};
+
+use crate::{
+    error::ExecutionError,
+    executor::ExecutionContext,
+    health::Health,
+    nats::{execution_context, handle_execution, receive_span, reply_headers},
+    shutdown::Shutdown,
+    subjects::Subjects,
+    utils::to_name_token,
+    workflows::Workflows,
+};
+
+pub const SERVICE_NAME: &str = "deadlift";
+
+const MAX_CONCURRENT_EXECUTIONS: usize = 100;
+
+/// Registers the agent as a NATS micro service, discoverable with `nats micro ls`
+///
+/// `PING`, `INFO` and `STATS` are answered by the service itself; the `health` endpoint at
+/// `<prefix>.<account>.agents.<service id>.health` replies with the health report, and an endpoint
+/// per workflow at `<prefix>.<account>.workflows.<workflow>` runs it; see [`Subjects`]. Workflow
+/// endpoints share a queue group, so requests are spread across the agents running the workflow.
+/// Workflow names map to distinct endpoint tokens, as colliding ones are rejected on load.
+///
+/// Workflow replies carry the execution id and trace context headers, like replies on the
+/// execution subjects. The service API can't add headers to a response, so they are published
+/// directly and aren't counted in the workflow endpoint stats.
+///
+/// When workflows are loaded or unloaded, in-flight requests are answered and the service is
+/// registered again with the new endpoints. The thread ends once draining and in-flight workflow
+/// requests have been answered.
+pub async fn start_service_thread(
+    nc: async_nats::Client,
+    subjects: Subjects,
+    health: Arc<Health>,
+    workflows: Arc<Workflows>,
+    shutdown: Shutdown,
+) -> Result<JoinHandle<()>> {
+    let mut changes = workflows.subscribe();
+    changes.borrow_and_update();
+
+    let registration = Registration::register(&nc, &subjects, &workflows).await?;
+
+    Ok(tokio::task::spawn(async move {
+        let mut registration = Some(registration);
+
+        loop {
+            let current = match registration.take() {
+                Some(registration) => registration,
+                None => match Registration::register(&nc, &subjects, &workflows).await {
+                    Ok(registration) => registration,
+                    Err(e) => {
+                        error!("{e}");
+                        return;
+                    }
+                },
+            };
+
+            if !current
+                .serve(&nc, &health, &workflows, &shutdown, &mut changes)
+                .await
+            {
+                return;
+            }
+        }
+    }))
+}
+
+struct Registration {
+    service: service::Service,
+    health_endpoint: service::endpoint::Endpoint,
+    workflow_endpoints: Vec<service::endpoint::Endpoint>,
+}
+
+impl Registration {
+    async fn register(
+        nc: &async_nats::Client,
+        subjects: &Subjects,
+        workflows: &Workflows,
+    ) -> Result<Self> {
+        let executors = workflows.executors();
+
+        let service = nc
+            .service_builder()
+            .description("deadlift agent")
+            .metadata(HashMap::from([(
+                String::from("workflows"),
+                executors
+                    .iter()
+                    .map(|executor| executor.workflow().name.clone())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )]))
+            .start(SERVICE_NAME, env!("CARGO_PKG_VERSION"))
+            .await
+            .map_err(|e| anyhow!("failed to register service; {e}"))?;
+
+        let service_id = service.info().await.id;
+        let health_endpoint = service
+            .group(subjects.agent_endpoints(&service_id))
+            .endpoint("health")
+            .await
+            .map_err(|e| anyhow!("failed to add health endpoint; {e}"))?;
+
+        let workflow_group = service.group(subjects.workflow_endpoints());
+        let mut workflow_endpoints = vec![];
+        for executor in &executors {
+            let workflow = executor.workflow();
+
+            workflow_endpoints.push(
+                workflow_group
+                    .endpoint_builder()
+                    .name(to_name_token(&workflow.name))
+                    .metadata(HashMap::from([
+                        (String::from("workflow"), workflow.name.clone()),
+                        (String::from("version"), workflow.version.to_string()),
+                    ]))
+                    .add(to_name_token(&workflow.name))
+                    .await
+                    .map_err(|e| {
+                        anyhow!(
+                            "failed to add endpoint for workflow '{}'; {e}",
+                            workflow.name
+                        )
+                    })?,
+            );
+        }
+
+        Ok(Self {
+            service,
+            health_endpoint,
+            workflow_endpoints,
+        })
+    }
+
+    /// Serves requests until draining or until workflows change; returns whether they changed
+    async fn serve(
+        self,
+        nc: &async_nats::Client,
+        health: &Health,
+        workflows: &Workflows,
+        shutdown: &Shutdown,
+        changes: &mut watch::Receiver<()>,
+    ) -> bool {
+        let Self {
+            service,
+            mut health_endpoint,
+            workflow_endpoints,
+        } = self;
+
+        let changed = AtomicBool::new(false);
+        let stop = async {
+            tokio::select! {
+                _ = shutdown.draining() => {}
+                // a closed channel disables the branch, as workflows never change again
+                Ok(()) = changes.changed() => changed.store(true, Ordering::Relaxed),
+            }
+        };
+
+        let health_requests = async {
+            while let Some(request) = health_endpoint.next().await {
+                let res = serde_json::to_vec(&health.report())
+                    .map(Into::into)
+                    .map_err(|e| service::error::Error {
+                        status: e.to_string(),
+                        code: 500,
+                    });
+
+                if let Err(e) = request.respond(res).await {
+                    error!("failed to respond to health request; {e}");
+                }
+            }
+        };
+
+        // without workflows there are no endpoints; requests are still awaited until stopped
+        let workflow_requests = futures::stream::select(
+            futures::stream::select_all(workflow_endpoints),
+            futures::stream::pending(),
+        )
+        .take_until(stop)
+        .for_each_concurrent(MAX_CONCURRENT_EXECUTIONS, |request| {
+            let token = request
+                .message
+                .subject
+                .rsplit('.')
+                .next()
+                .unwrap_or_default();
+            let executor = workflows.get_by_token(token);
+            let mut context = execution_context("service", request.message.headers.as_ref());
+            let span = receive_span(&request.message.subject, &mut context);
+
+            async move {
+                let subject = &request.message.subject;
+
+                let res = match executor {
+                    Some(executor) => {
+                        handle_execution(&executor, context.clone(), &request.message, None).await
+                    }
+                    None => Err(anyhow!("workflow is no longer loaded")),
+                };
+
+                let Some(reply) = request.message.reply.clone() else {
+                    return;
+                };
+                let (headers, payload) = workflow_reply(&context, res);
+
+                if let Err(e) = nc
+                    .publish_with_headers(reply, headers, payload.into())
+                    .await
+                {
+                    error!("failed to respond to '{subject}'; {e}");
+                }
+            }
+            .instrument(span)
+        });
+
+        // health requests are served until the workflow endpoints have drained
+        tokio::select! {
+            _ = health_requests => {}
+            _ = workflow_requests => {}
+        }
+
+        let changed = changed.load(Ordering::Relaxed);
+        if changed {
+            if let Err(e) = service.stop().await {
+                error!("failed to stop service; {e}");
+            }
+        }
+
+        changed
+    }
+}
+
+/// Reply to a workflow request, with the service error headers when the execution failed
+fn workflow_reply(
+    context: &ExecutionContext,
+    res: Result<Vec<u8>>,
+) -> (async_nats::HeaderMap, Vec<u8>) {
+    let mut headers = reply_headers(context);
+
+    match res {
+        Ok(output) => (headers, output),
+        Err(e) => {
+            headers.insert(NATS_SERVICE_ERROR, e.to_string().as_str());
+            headers.insert(NATS_SERVICE_ERROR_CODE, error_code(&e).to_string().as_str());
+            (headers, vec![])
+        }
+    }
+}
+
+/// Service error code of a failed execution; 400 for requests the caller has to change, such as
+/// a schema violation or a version the agent doesn't run, and 500 otherwise
+fn error_code(e: &anyhow::Error) -> usize {
+    if e.downcast_ref::<ExecutionError>().is_some() {
+        400
+    } else {
+        500
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nats::EXECUTION_ID_HEADER;
+
+    #[test]
+    fn test_error_code() {
+        let mismatch = anyhow::Error::from(ExecutionError::VersionMismatch {
+            workflow: "do some math".to_string(),
+            running: 2,
+            requested: 1,
+        });
+        assert_eq!(error_code(&mismatch), 400);
+
+        let invalid_id = anyhow::Error::from(ExecutionError::InvalidExecutionId {
+            id: "a.b".to_string(),
+        });
+        assert_eq!(error_code(&invalid_id), 400);
+
+        assert_eq!(error_code(&anyhow!("plugin trapped")), 500);
+    }
+
+    #[test]
+    fn test_workflow_reply() {
+        let context = ExecutionContext::new("service");
+
+        let (headers, payload) = workflow_reply(&context, Ok(b"10".to_vec()));
+        assert_eq!(payload, b"10");
+        assert_eq!(
+            headers.get(EXECUTION_ID_HEADER).map(|id| id.as_str()),
+            Some(context.id.as_str())
+        );
+        assert!(headers.get(NATS_SERVICE_ERROR_CODE).is_none());
+
+        let (headers, payload) = workflow_reply(&context, Err(anyhow!("plugin trapped")));
+        assert!(payload.is_empty());
+        assert_eq!(
+            headers.get(EXECUTION_ID_HEADER).map(|id| id.as_str()),
+            Some(context.id.as_str())
+        );
+        assert_eq!(
+            headers.get(NATS_SERVICE_ERROR).map(|e| e.as_str()),
+            Some("plugin trapped")
+        );
+        assert_eq!(
+            headers
+                .get(NATS_SERVICE_ERROR_CODE)
+                .map(|code| code.as_str()),
+            Some("500")
+        );
+    }
+}
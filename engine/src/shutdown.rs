@@ -0,0 +1,140 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tokio::sync::watch;
+
+/// How long stream triggers get to nack unfinished messages once the grace period is over
+pub(crate) const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Running,
+
+    /// No new executions are accepted; in-flight executions finish
+    Draining,
+
+    /// The grace period is over; unfinished executions are abandoned
+    Stopped,
+}
+
+/// Signals engine threads to drain and stop
+pub struct ShutdownController {
+    tx: Arc<watch::Sender<Phase>>,
+}
+
+/// Shutdown state observed by engine threads
+#[derive(Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<Phase>,
+}
+
+pub fn shutdown_channel() -> (ShutdownController, Shutdown) {
+    let (tx, rx) = watch::channel(Phase::Running);
+
+    (ShutdownController { tx: Arc::new(tx) }, Shutdown { rx })
+}
+
+impl ShutdownController {
+    pub fn drain(&self) {
+        self.tx
+            .send_if_modified(|phase| advance(phase, Phase::Draining));
+    }
+
+    pub fn stop(&self) {
+        self.tx
+            .send_if_modified(|phase| advance(phase, Phase::Stopped));
+    }
+}
+
+impl Shutdown {
+    /// Resolves once executions should no longer be accepted
+    pub fn draining(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.wait_for(Phase::Draining)
+    }
+
+    /// Resolves once unfinished executions should be abandoned
+    pub fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.wait_for(Phase::Stopped)
+    }
+
+    /// Channel that follows this one, and can also be drained and stopped on its own, e.g. to
+    /// unload a single workflow
+    pub fn child(&self) -> (ShutdownController, Shutdown) {
+        let (controller, child) = shutdown_channel();
+        let tx = controller.tx.clone();
+        let parent = self.clone();
+
+        tokio::task::spawn(async move {
+            let follow = async {
+                parent.draining().await;
+                tx.send_if_modified(|phase| advance(phase, Phase::Draining));
+
+                parent.stopped().await;
+                tx.send_if_modified(|phase| advance(phase, Phase::Stopped));
+            };
+
+            // ends once nothing observes the child anymore
+            tokio::select! {
+                _ = follow => {}
+                _ = tx.closed() => {}
+            }
+        });
+
+        (controller, child)
+    }
+
+    /// For blocking threads, which check between iterations
+    pub fn is_draining(&self) -> bool {
+        *self.rx.borrow() >= Phase::Draining
+    }
+
+    fn wait_for(&self, phase: Phase) -> impl Future<Output = ()> + Send + 'static {
+        let mut rx = self.rx.clone();
+
+        async move {
+            // a dropped controller also counts as shutdown
+            let _ = rx.wait_for(|current| *current >= phase).await;
+        }
+    }
+}
+
+fn advance(phase: &mut Phase, next: Phase) -> bool {
+    if *phase < next {
+        *phase = next;
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_phases() {
+        let (controller, shutdown) = shutdown_channel();
+        assert!(!shutdown.is_draining());
+
+        controller.stop();
+        controller.drain();
+
+        // stopping implies draining, and phases never go back
+        assert!(shutdown.is_draining());
+        shutdown.draining().await;
+        shutdown.stopped().await;
+    }
+
+    #[tokio::test]
+    async fn test_child_follows_parent() {
+        let (controller, shutdown) = shutdown_channel();
+        let (child_controller, child) = shutdown.child();
+
+        child_controller.drain();
+        assert!(child.is_draining());
+        assert!(!shutdown.is_draining());
+
+        let (_other_controller, other) = shutdown.child();
+        controller.stop();
+        other.stopped().await;
+    }
+}
@@ -0,0 +1,230 @@
+use anyhow::{anyhow, Result};
+
+use crate::{config::NatsConfig, utils::to_name_token};
+
+pub const DEFAULT_SUBJECT_PREFIX: &str = "deadlift";
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+/// Version token served by agents running any version of a workflow
+pub const LATEST_VERSION: &str = "latest";
+
+/// Subjects executions are requested on, and those of the service and published logs
+///
+/// ```text
+/// <prefix>.<account>.workflows.<workflow>.<version>.run                 runs the workflow
+/// <prefix>.<account>.workflows.<workflow>.<version>.stages.<stage>.run  runs a single stage
+/// <prefix>.<account>.workflows.<workflow>                               service endpoint
+/// <prefix>.<account>.agents.<service id>.health                         service health endpoint
+/// <prefix>.<account>.logs.<workflow>                                    published logs
+/// ```
+///
+/// The prefix and account come from the nats config, names are converted to subject tokens, and
+/// `latest` in place of the version reaches agents running any version of the workflow.
+#[derive(Clone, Debug)]
+pub struct Subjects {
+    /// `<prefix>.<account>`
+    root: String,
+    workflows: String,
+}
+
+impl Subjects {
+    pub fn new(config: &NatsConfig) -> Self {
+        let root = format!(
+            "{}.{}",
+            config.subject_prefix,
+            to_name_token(&config.account)
+        );
+
+        Self {
+            workflows: format!("{root}.workflows"),
+            root,
+        }
+    }
+
+    /// Runs a workflow; any version unless one is given
+    pub fn workflow(&self, workflow: &str, version: Option<u32>) -> String {
+        format!(
+            "{}.{}.run",
+            self.workflows,
+            Self::version_of(workflow, version)
+        )
+    }
+
+    /// Runs a single stage of a workflow; any version unless one is given
+    pub fn stage(&self, workflow: &str, version: Option<u32>, stage: &str) -> String {
+        self.stage_subject(workflow, version, &to_name_token(stage))
+    }
+
+    /// Subjects an agent running a version of a workflow subscribes to: the workflow and its
+    /// stages, at that version and at `latest`
+    pub fn served(&self, workflow: &str, version: u32) -> Vec<String> {
+        [Some(version), None]
+            .into_iter()
+            .flat_map(|version| {
+                [
+                    self.workflow(workflow, version),
+                    self.stage_subject(workflow, version, "*"),
+                ]
+            })
+            .collect()
+    }
+
+    /// Service group of the per-workflow endpoints, named by workflow token
+    pub fn workflow_endpoints(&self) -> &str {
+        &self.workflows
+    }
+
+    /// Service group of an agent's own endpoints, such as `health`
+    pub fn agent_endpoints(&self, service_id: &str) -> String {
+        format!("{}.agents.{service_id}", self.root)
+    }
+
+    /// Logs published for a workflow
+    pub fn logs(&self, workflow: &str) -> String {
+        format!("{}.logs.{}", self.root, to_name_token(workflow))
+    }
+
+    /// Logs published for every workflow
+    pub fn all_logs(&self) -> String {
+        format!("{}.logs.>", self.root)
+    }
+
+    /// Stage token of a stage subject, or `None` for a workflow subject
+    pub fn stage_token<'a>(&self, subject: &'a str) -> Option<&'a str> {
+        let tokens = subject
+            .strip_prefix(self.workflows.as_str())?
+            .strip_prefix('.')?
+            .split('.')
+            .collect::<Vec<_>>();
+
+        match tokens.as_slice() {
+            [_workflow, _version, "stages", stage, "run"] => Some(*stage),
+            _ => None,
+        }
+    }
+
+    fn stage_subject(&self, workflow: &str, version: Option<u32>, stage_token: &str) -> String {
+        format!(
+            "{}.{}.stages.{stage_token}.run",
+            self.workflows,
+            Self::version_of(workflow, version)
+        )
+    }
+
+    fn version_of(workflow: &str, version: Option<u32>) -> String {
+        let version = version
+            .map(|version| version.to_string())
+            .unwrap_or_else(|| LATEST_VERSION.to_string());
+
+        format!("{}.{version}", to_name_token(workflow))
+    }
+}
+
+/// Checks a subject prefix: one or more dot separated tokens, without whitespace or wildcards
+pub fn validate_subject_prefix(prefix: &str) -> Result<()> {
+    let valid = prefix.split('.').all(|token| {
+        !token.is_empty()
+            && !token
+                .chars()
+                .any(|c| c.is_whitespace() || c == '*' || c == '>')
+    });
+
+    if !valid {
+        return Err(anyhow!(
+            "invalid subject prefix '{prefix}'; it must be dot separated tokens without whitespace, '*' or '>'"
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subjects() {
+        let config = serde_yaml::from_str::<NatsConfig>("account: acme corp").unwrap();
+        let subjects = Subjects::new(&config);
+
+        assert_eq!(
+            subjects.workflow("do some math", Some(2)),
+            "deadlift.acme_corp.workflows.do_some_math.2.run"
+        );
+        assert_eq!(
+            subjects.stage("do some math", None, "add"),
+            "deadlift.acme_corp.workflows.do_some_math.latest.stages.add.run"
+        );
+        assert_eq!(
+            subjects.served("math", 2),
+            [
+                "deadlift.acme_corp.workflows.math.2.run",
+                "deadlift.acme_corp.workflows.math.2.stages.*.run",
+                "deadlift.acme_corp.workflows.math.latest.run",
+                "deadlift.acme_corp.workflows.math.latest.stages.*.run",
+            ]
+        );
+
+        assert_eq!(
+            subjects.stage_token(&subjects.stage("stages", Some(1), "add")),
+            Some("add")
+        );
+        assert_eq!(
+            subjects.stage_token(&subjects.workflow("stages", None)),
+            None
+        );
+
+        assert_eq!(
+            subjects.workflow_endpoints(),
+            "deadlift.acme_corp.workflows"
+        );
+        assert_eq!(
+            subjects.agent_endpoints("abc"),
+            "deadlift.acme_corp.agents.abc"
+        );
+        assert_eq!(
+            subjects.logs("do some math"),
+            "deadlift.acme_corp.logs.do_some_math"
+        );
+        assert_eq!(subjects.all_logs(), "deadlift.acme_corp.logs.>");
+    }
+
+    #[test]
+    fn test_validate_subject_prefix() {
+        assert!(validate_subject_prefix("deadlift").is_ok());
+        assert!(validate_subject_prefix("acme.deadlift").is_ok());
+
+        for prefix in [
+            "",
+            "acme.",
+            ".deadlift",
+            "acme..deadlift",
+            "acme deadlift",
+            "acme.*",
+            ">",
+        ] {
+            assert!(validate_subject_prefix(prefix).is_err(), "{prefix}");
+        }
+    }
+
+    // agents load every workflow placed on them, so each must be served on its own subjects;
+    // a shared subject would hand requests for one workflow to agents running another
+    #[test]
+    fn test_served_subjects_are_per_workflow() {
+        let subjects = Subjects::new(&serde_yaml::from_str::<NatsConfig>("{}").unwrap());
+
+        let math = subjects.served("math", 1);
+        let orders = subjects.served("orders", 1);
+
+        assert!(math
+            .iter()
+            .all(|subject| subject.starts_with("deadlift.default.workflows.math.")));
+        assert!(orders
+            .iter()
+            .all(|subject| subject.starts_with("deadlift.default.workflows.orders.")));
+        assert!(math
+            .iter()
+            .chain(&orders)
+            .all(|subject| !subject.starts_with("deadlift.executions")));
+    }
+}
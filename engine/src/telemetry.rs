@@ -0,0 +1,178 @@
+use anyhow::Result;
+use tracing::Span;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::{
+    config::{LogFormat, TelemetryConfig},
+    logs::LogLayer,
+};
+
+/// Installs the global tracing subscriber; replaces the extism log callback so plugin logs are
+/// printed alongside engine events, within the span of their plugin call
+pub fn init_telemetry(config: &TelemetryConfig) -> Result<()> {
+    let filter = match &config.log_filter {
+        Some(directives) => EnvFilter::try_new(directives)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+
+    let (text_layer, json_layer) = match config.log_format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer()), None),
+        LogFormat::Json => (None, Some(tracing_subscriber::fmt::layer().json())),
+    };
+
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(LogLayer)
+        .with(text_layer)
+        .with(json_layer);
+
+    #[cfg(feature = "otel")]
+    let registry = registry.with(otel::layer(config)?);
+
+    // an embedding application may have installed its own subscriber
+    let _ = registry.try_init();
+
+    #[cfg(not(feature = "otel"))]
+    if config.otlp_endpoint.is_some() {
+        tracing::warn!("otlp export is disabled; engine was built without the otel feature");
+    }
+
+    Ok(())
+}
+
+/// Flushes spans that have not been exported yet
+pub fn shutdown_telemetry() {
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Continues the caller's W3C trace context on the span
+pub(crate) fn set_remote_parent(span: &Span, traceparent: Option<&str>) {
+    #[cfg(feature = "otel")]
+    if let Some(traceparent) = traceparent {
+        otel::set_remote_parent(span, traceparent);
+    }
+
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, traceparent);
+}
+
+/// W3C trace context of the span, when spans are exported
+pub(crate) fn traceparent(span: &Span) -> Option<String> {
+    #[cfg(feature = "otel")]
+    return otel::traceparent(span);
+
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = span;
+        None
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+    use opentelemetry::{propagation::TextMapPropagator, trace::TracerProvider, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, Resource};
+    use tracing::{Span, Subscriber};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::{registry::LookupSpan, Layer};
+
+    use crate::config::TelemetryConfig;
+
+    const TRACEPARENT: &str = "traceparent";
+
+    pub(super) fn layer<S>(config: &TelemetryConfig) -> Result<Option<impl Layer<S>>>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(endpoint) = &config.otlp_endpoint else {
+            return Ok(None);
+        };
+
+        let provider = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(opentelemetry_sdk::trace::Config::default().with_resource(
+                Resource::new([KeyValue::new("service.name", config.service_name.clone())]),
+            ))
+            .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+        let tracer = provider.tracer("deadlift");
+        opentelemetry::global::set_tracer_provider(provider);
+
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }
+
+    pub(super) fn set_remote_parent(span: &Span, traceparent: &str) {
+        let carrier = HashMap::from([(TRACEPARENT.to_string(), traceparent.to_string())]);
+        span.set_parent(TraceContextPropagator::new().extract(&carrier));
+    }
+
+    pub(super) fn traceparent(span: &Span) -> Option<String> {
+        let mut carrier = HashMap::new();
+        TraceContextPropagator::new().inject_context(&span.context(), &mut carrier);
+
+        carrier.remove(TRACEPARENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_init_telemetry_rejects_invalid_filter() {
+        let config = serde_yaml::from_str::<TelemetryConfig>("log_filter: deadlift=loud").unwrap();
+
+        assert!(init_telemetry(&config).is_err());
+    }
+
+    #[cfg(not(feature = "otel"))]
+    #[test]
+    fn test_trace_context_without_otel() {
+        let span = tracing::info_span!("receive");
+        set_remote_parent(&span, Some(TRACEPARENT));
+
+        assert_eq!(traceparent(&span), None);
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn test_trace_context_continues_remote_parent() {
+        use opentelemetry::trace::TracerProvider;
+
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("receive");
+            set_remote_parent(&span, Some(TRACEPARENT));
+
+            let traceparent = traceparent(&span).unwrap();
+            let parts = traceparent.split('-').collect::<Vec<_>>();
+
+            // same trace, new span
+            assert_eq!(parts[1], "4bf92f3577b34da6a3ce929d0e0e4736");
+            assert_ne!(parts[2], "00f067aa0ba902b7");
+            assert_eq!(parts[3], "01");
+
+            let span = tracing::info_span!("scheduled");
+            set_remote_parent(&span, None);
+            assert_ne!(
+                super::traceparent(&span).unwrap().split('-').nth(1),
+                Some("4bf92f3577b34da6a3ce929d0e0e4736")
+            );
+        });
+    }
+}
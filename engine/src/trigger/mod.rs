@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio::task::JoinHandle;
+
+use crate::{
+    config::TriggerConfig,
+    executor::Executor,
+    runtime::{supervise, FatalSender},
+    shutdown::Shutdown,
+};
+
+mod nats;
+mod postgres;
+
+/// Starts a supervised thread per trigger declared by the workflow
+pub(crate) async fn start_trigger_threads(
+    nc: async_nats::Client,
+    executor: Arc<Executor>,
+    shutdown: Shutdown,
+    fatal: FatalSender,
+) -> Result<Vec<JoinHandle<()>>> {
+    let mut handles = vec![];
+
+    for idx in 0..executor.workflow().triggers.len() {
+        let start = {
+            let nc = nc.clone();
+            let executor = executor.clone();
+            let shutdown = shutdown.clone();
+
+            move || start_trigger_thread(nc.clone(), executor.clone(), idx, shutdown.clone())
+        };
+
+        handles.push(
+            supervise(
+                format!("trigger {idx} of '{}'", executor.workflow().name),
+                start,
+                shutdown.clone(),
+                fatal.clone(),
+            )
+            .await?,
+        );
+    }
+
+    Ok(handles)
+}
+
+async fn start_trigger_thread(
+    nc: async_nats::Client,
+    executor: Arc<Executor>,
+    idx: usize,
+    shutdown: Shutdown,
+) -> Result<JoinHandle<()>> {
+    let trigger = executor
+        .workflow()
+        .triggers
+        .get(idx)
+        .ok_or_else(|| anyhow!("trigger {idx} does not exist"))?
+        .clone();
+
+    let handle = match &trigger {
+        TriggerConfig::Subject {
+            subject,
+            queue_group,
+        } => {
+            nats::start_subject_thread(nc, executor, subject.clone(), queue_group.clone(), shutdown)
+                .await?
+        }
+        TriggerConfig::Stream { .. } => {
+            nats::start_stream_thread(nc, executor, &trigger, shutdown).await?
+        }
+        TriggerConfig::PostgresNotify { url, channel } => {
+            postgres::start_notify_thread(executor, url.clone(), channel.clone(), shutdown)
+        }
+        TriggerConfig::PostgresReplication {
+            url,
+            slot,
+            create_slot,
+            tables,
+            poll_interval_ms,
+        } => postgres::start_replication_thread(
+            executor,
+            url.clone(),
+            slot.clone(),
+            *create_slot,
+            tables.clone(),
+            std::time::Duration::from_millis(*poll_interval_ms),
+            shutdown,
+        ),
+    };
+
+    Ok(handle)
+}
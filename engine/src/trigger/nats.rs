@@ -0,0 +1,361 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_nats::jetstream::{
+    self,
+    consumer::{self, pull, AckPolicy, DeliverPolicy},
+    AckKind,
+};
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use tokio::task::JoinHandle;
+use tracing::{error, info_span, warn, Instrument};
+
+use crate::{
+    config::{StreamDeliverPolicy, TriggerConfig},
+    error::ExecutionError,
+    executor::Executor,
+    nats::{execution_context, receive_span, reply_headers, serve_until_draining},
+    shutdown::Shutdown,
+    utils::to_name_token,
+};
+
+const MAX_CONCURRENT_EXECUTIONS: usize = 100;
+
+/// Time a stream message may go without an ack or progress before it is redelivered
+const ACK_WAIT: Duration = Duration::from_secs(30);
+
+/// Running executions report progress this often, so that they aren't redelivered however long
+/// they run
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Delay before each redelivery of a failed stream message
+const REDELIVERY_BACKOFF: [Duration; 4] = [
+    Duration::from_secs(30),
+    Duration::from_secs(60),
+    Duration::from_secs(5 * 60),
+    Duration::from_secs(15 * 60),
+];
+
+pub(super) async fn start_subject_thread(
+    nc: async_nats::Client,
+    executor: Arc<Executor>,
+    subject: String,
+    queue_group: Option<String>,
+    shutdown: Shutdown,
+) -> Result<JoinHandle<()>> {
+    let queue_group = queue_group
+        .unwrap_or_else(|| format!("deadlift_{}", to_name_token(&executor.workflow().name)));
+
+    let trigger = format!("subject:{subject}");
+    let subscriber = nc.queue_subscribe(subject, queue_group).await?;
+
+    Ok(tokio::task::spawn(async move {
+        serve_until_draining(
+            vec![subscriber],
+            MAX_CONCURRENT_EXECUTIONS,
+            &shutdown,
+            |msg| {
+                let nc = nc.clone();
+                let executor = executor.clone();
+                let mut context = execution_context(trigger.clone(), msg.headers.as_ref());
+                let span = receive_span(&msg.subject, &mut context);
+
+                async move {
+                    let res = executor
+                        .execute_async(context.clone(), msg.payload.to_vec())
+                        .await;
+
+                    match msg.reply {
+                        Some(reply) => {
+                            let payload = res.unwrap_or_else(|e| e.to_string().into_bytes());
+                            if let Err(e) = nc
+                                .publish_with_headers(
+                                    reply,
+                                    reply_headers(&context),
+                                    payload.into(),
+                                )
+                                .instrument(info_span!("reply"))
+                                .await
+                            {
+                                error!("failed to reply to '{}'; {e}", msg.subject);
+                            }
+                        }
+                        None => {
+                            if let Err(e) = res {
+                                error!("execution for '{}' failed; {e}", msg.subject);
+                            }
+                        }
+                    }
+                }
+                .instrument(span)
+            },
+        )
+        .await;
+    }))
+}
+
+pub(super) async fn start_stream_thread(
+    nc: async_nats::Client,
+    executor: Arc<Executor>,
+    trigger: &TriggerConfig,
+    shutdown: Shutdown,
+) -> Result<JoinHandle<()>> {
+    let TriggerConfig::Stream {
+        stream,
+        consumer,
+        filter_subjects,
+        deliver_policy,
+        start_sequence,
+        start_time,
+        max_deliver,
+    } = trigger
+    else {
+        return Err(anyhow!("expected a stream trigger"));
+    };
+
+    let durable_name = consumer.clone().unwrap_or_else(|| {
+        default_durable_name(&executor.workflow().name, stream, filter_subjects)
+    });
+
+    let deliver_policy = match (start_sequence, start_time) {
+        (Some(start_sequence), _) => DeliverPolicy::ByStartSequence {
+            start_sequence: *start_sequence,
+        },
+        (None, Some(start_time)) => {
+            let start_time = chrono::DateTime::parse_from_rfc3339(start_time)
+                .map_err(|e| anyhow!("invalid start_time '{start_time}'; {e}"))?;
+            DeliverPolicy::ByStartTime {
+                start_time: time::OffsetDateTime::from_unix_timestamp(start_time.timestamp())?,
+            }
+        }
+        (None, None) => match deliver_policy {
+            StreamDeliverPolicy::All => DeliverPolicy::All,
+            StreamDeliverPolicy::New => DeliverPolicy::New,
+            StreamDeliverPolicy::Last => DeliverPolicy::Last,
+            StreamDeliverPolicy::LastPerSubject => DeliverPolicy::LastPerSubject,
+        },
+    };
+
+    let trigger = format!("stream:{stream}");
+    let js = jetstream::new(nc);
+    let stream = js.get_stream(stream).await?;
+    let config = pull::Config {
+        durable_name: Some(durable_name.clone()),
+        filter_subjects: filter_subjects.clone(),
+        deliver_policy,
+        ack_policy: AckPolicy::Explicit,
+        ack_wait: ACK_WAIT,
+        max_deliver: *max_deliver,
+        ..Default::default()
+    };
+    let consumer = stream
+        .get_or_create_consumer(&durable_name, config.clone())
+        .await?;
+
+    // an existing durable consumer keeps its config
+    let drift = config_drift(&consumer.cached_info().config, &config);
+    if !drift.is_empty() {
+        warn!(
+            "consumer '{durable_name}' exists with a different {}; delete it to apply the trigger config",
+            drift.join(", ")
+        );
+    }
+
+    let messages = consumer.messages().await?;
+
+    Ok(tokio::task::spawn(async move {
+        messages
+            .take_until(shutdown.draining())
+            .for_each_concurrent(MAX_CONCURRENT_EXECUTIONS, |msg| {
+                let executor = executor.clone();
+                let durable_name = durable_name.clone();
+                let trigger = trigger.clone();
+                let stopped = shutdown.stopped();
+
+                async move {
+                    let msg = match msg {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            error!("consumer '{durable_name}' failed to receive; {e}");
+                            return;
+                        }
+                    };
+
+                    let mut context = execution_context(trigger, msg.headers.as_ref());
+                    let span = receive_span(&msg.subject, &mut context);
+
+                    let mut execution = std::pin::pin!(executor
+                        .execute_async(context, msg.payload.to_vec())
+                        .instrument(span));
+                    let mut stopped = std::pin::pin!(stopped);
+                    let delivered = msg.info().map(|info| info.delivered).unwrap_or(1);
+                    let mut progress = tokio::time::interval_at(
+                        tokio::time::Instant::now() + PROGRESS_INTERVAL,
+                        PROGRESS_INTERVAL,
+                    );
+
+                    // executions still running after the grace period are redelivered
+                    let ack = loop {
+                        tokio::select! {
+                            res = &mut execution => {
+                                if let Err(e) = &res {
+                                    error!("execution for '{}' failed; {e}", msg.subject);
+                                }
+                                break msg.ack_with(ack_kind(&res, delivered)).await;
+                            }
+                            _ = progress.tick() => {
+                                if let Err(e) = msg.ack_with(AckKind::Progress).await {
+                                    error!("consumer '{durable_name}' failed to report progress; {e}");
+                                }
+                            }
+                            _ = &mut stopped => break msg.ack_with(AckKind::Nak(None)).await,
+                        }
+                    };
+
+                    if let Err(e) = ack {
+                        error!("consumer '{durable_name}' failed to ack; {e}");
+                    }
+                }
+            })
+            .await;
+    }))
+}
+
+/// Durable consumer name that only depends on the workflow and what the trigger consumes, so
+/// reordering triggers keeps their consumers
+fn default_durable_name(workflow: &str, stream: &str, filter_subjects: &[String]) -> String {
+    let mut filter_subjects = filter_subjects.to_vec();
+    filter_subjects.sort();
+
+    let mut hasher = Sha256::new();
+    hasher.update(stream);
+    for filter_subject in filter_subjects {
+        hasher.update([0]);
+        hasher.update(filter_subject);
+    }
+
+    format!(
+        "deadlift_{}_{}",
+        to_name_token(workflow),
+        hex::encode(&hasher.finalize()[..8])
+    )
+}
+
+/// Settings of an existing consumer that differ from the trigger's
+fn config_drift(existing: &consumer::Config, wanted: &pull::Config) -> Vec<&'static str> {
+    // a single filter subject may come back as `filter_subject`
+    let mut existing_filters = existing.filter_subjects.clone();
+    if !existing.filter_subject.is_empty() {
+        existing_filters.push(existing.filter_subject.clone());
+    }
+    existing_filters.sort();
+    let mut wanted_filters = wanted.filter_subjects.clone();
+    wanted_filters.sort();
+
+    [
+        ("filter_subjects", existing_filters != wanted_filters),
+        (
+            "deliver_policy",
+            existing.deliver_policy != wanted.deliver_policy,
+        ),
+        ("max_deliver", existing.max_deliver != wanted.max_deliver),
+        ("ack_wait", existing.ack_wait != wanted.ack_wait),
+        ("backoff", existing.backoff != wanted.backoff),
+    ]
+    .into_iter()
+    .filter_map(|(name, differs)| differs.then_some(name))
+    .collect()
+}
+
+/// Acks successful executions, terminates messages that can never succeed, such as schema
+/// violations, and naks the rest with a delay growing with the deliveries so far
+fn ack_kind(res: &Result<Vec<u8>>, delivered: i64) -> AckKind {
+    match res {
+        Ok(_) => AckKind::Ack,
+        Err(e) if e.downcast_ref::<ExecutionError>().is_some() => AckKind::Term,
+        Err(_) => {
+            let idx = (delivered.max(1) as usize - 1).min(REDELIVERY_BACKOFF.len() - 1);
+            AckKind::Nak(Some(REDELIVERY_BACKOFF[idx]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::PayloadKind;
+
+    #[test]
+    fn test_default_durable_name() {
+        let filters = |filters: &[&str]| filters.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let name = default_durable_name("order sync", "ORDERS", &filters(&["a.*", "b.>"]));
+        assert!(name.starts_with("deadlift_order_sync_"));
+        assert_eq!(
+            name,
+            default_durable_name("order sync", "ORDERS", &filters(&["b.>", "a.*"]))
+        );
+        assert_ne!(
+            name,
+            default_durable_name("order sync", "ORDERS", &filters(&["a.*"]))
+        );
+        assert_ne!(
+            name,
+            default_durable_name("order sync", "SHIPMENTS", &filters(&["a.*", "b.>"]))
+        );
+    }
+
+    #[test]
+    fn test_config_drift() {
+        let wanted = pull::Config {
+            filter_subjects: vec!["orders.>".to_string()],
+            deliver_policy: DeliverPolicy::New,
+            ack_wait: ACK_WAIT,
+            max_deliver: 5,
+            ..Default::default()
+        };
+        let existing = consumer::Config {
+            filter_subject: "orders.>".to_string(),
+            deliver_policy: DeliverPolicy::New,
+            ack_wait: ACK_WAIT,
+            max_deliver: 5,
+            ..Default::default()
+        };
+        assert!(config_drift(&existing, &wanted).is_empty());
+
+        // e.g. a consumer created with an earlier config
+        let existing = consumer::Config {
+            deliver_policy: DeliverPolicy::All,
+            max_deliver: 3,
+            backoff: REDELIVERY_BACKOFF[..2].to_vec(),
+            ..existing
+        };
+        assert_eq!(
+            config_drift(&existing, &wanted),
+            ["deliver_policy", "max_deliver", "backoff"]
+        );
+    }
+
+    #[test]
+    fn test_ack_kind() {
+        assert!(matches!(ack_kind(&Ok(vec![]), 1), AckKind::Ack));
+
+        let violation = ExecutionError::SchemaViolation {
+            stage: None,
+            payload: PayloadKind::Input,
+            pointer: "/id".to_string(),
+            message: "missing".to_string(),
+        };
+        assert!(matches!(ack_kind(&Err(violation.into()), 1), AckKind::Term));
+
+        assert!(matches!(
+            ack_kind(&Err(anyhow!("plugin trapped")), 1),
+            AckKind::Nak(Some(delay)) if delay == REDELIVERY_BACKOFF[0]
+        ));
+        assert!(matches!(
+            ack_kind(&Err(anyhow!("plugin trapped")), 20),
+            AckKind::Nak(Some(delay)) if delay == REDELIVERY_BACKOFF[3]
+        ));
+    }
+}
@@ -0,0 +1,521 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use postgres::{fallible_iterator::FallibleIterator, Client, NoTls};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+use tracing::error;
+
+use crate::{
+    executor::{ExecutionContext, Executor},
+    shutdown::Shutdown,
+};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_CHANGES_PER_POLL: i32 = 1000;
+
+/// Failed executions of a row change after which it is logged and skipped, so that one bad
+/// change can't hold back the slot, and the WAL kept for it, forever
+const MAX_CHANGE_ATTEMPTS: u32 = 5;
+
+pub(super) fn start_notify_thread(
+    executor: Arc<Executor>,
+    url: String,
+    channel: String,
+    shutdown: Shutdown,
+) -> JoinHandle<()> {
+    let trigger = format!("postgres_notify:{channel}");
+
+    tokio::task::spawn_blocking(move || {
+        while !shutdown.is_draining() {
+            let run = with_new_context(&trigger, |context, input| {
+                executor.execute(context, input).map(|_| ())
+            });
+            if let Err(e) = listen(&url, &channel, &shutdown, run) {
+                error!("postgres listener on '{channel}' failed; {e}");
+                std::thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    })
+}
+
+pub(super) fn start_replication_thread(
+    executor: Arc<Executor>,
+    url: String,
+    slot: String,
+    create_slot: bool,
+    tables: Vec<String>,
+    poll_interval: Duration,
+    shutdown: Shutdown,
+) -> JoinHandle<()> {
+    let trigger = format!("postgres_replication:{slot}");
+
+    tokio::task::spawn_blocking(move || {
+        // kept across reconnects, as a failed change is retried on a new connection
+        let mut failed = None;
+
+        while !shutdown.is_draining() {
+            let run = with_new_context(&trigger, |context, input| {
+                executor.execute(context, input).map(|_| ())
+            });
+            if let Err(e) = replicate(
+                &url,
+                &slot,
+                create_slot,
+                &tables,
+                poll_interval,
+                &shutdown,
+                &mut failed,
+                run,
+            ) {
+                error!("postgres replication from slot '{slot}' failed; {e}");
+                std::thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    })
+}
+
+/// Runs `execute` with a new execution context for every input, so that each notification or
+/// change is its own execution
+fn with_new_context<'a>(
+    trigger: &'a str,
+    mut execute: impl FnMut(&ExecutionContext, Vec<u8>) -> Result<()> + 'a,
+) -> impl FnMut(Vec<u8>) -> Result<()> + 'a {
+    move |input| execute(&ExecutionContext::new(trigger), input)
+}
+
+/// Runs the workflow for every notification until the connection fails, or returns once
+/// draining
+fn listen(
+    url: &str,
+    channel: &str,
+    shutdown: &Shutdown,
+    mut run: impl FnMut(Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    let mut client = Client::connect(url, NoTls)?;
+    client.batch_execute(&format!("LISTEN {}", quote_ident(channel)))?;
+
+    while !shutdown.is_draining() {
+        {
+            let mut notifications = client.notifications();
+            let mut iter = notifications.timeout_iter(NOTIFICATION_TIMEOUT);
+
+            while let Some(notification) = iter.next()? {
+                let input = serde_json::to_vec(&json!({
+                    "channel": notification.channel(),
+                    "payload": parse_json_or_string(notification.payload()),
+                }))?;
+
+                if let Err(e) = run(input) {
+                    error!("execution for notification on '{channel}' failed; {e}");
+                }
+            }
+        }
+
+        if client.is_closed() {
+            return Err(anyhow!("connection closed"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the workflow for every row change until the connection fails or a run fails, or
+/// returns once draining after the current poll
+///
+/// A change whose run failed [`MAX_CHANGE_ATTEMPTS`] times, counted in `failed`, is logged and
+/// skipped instead, so the slot advances past it.
+#[allow(clippy::too_many_arguments)]
+fn replicate(
+    url: &str,
+    slot: &str,
+    create_slot: bool,
+    tables: &[String],
+    poll_interval: Duration,
+    shutdown: &Shutdown,
+    failed: &mut Option<FailedChange>,
+    mut run: impl FnMut(Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    let mut client = Client::connect(url, NoTls)?;
+
+    let slot_exists = client
+        .query_opt(
+            "SELECT 1 FROM pg_replication_slots WHERE slot_name = $1",
+            &[&slot],
+        )?
+        .is_some();
+
+    if !slot_exists {
+        if !create_slot {
+            return Err(anyhow!("replication slot '{slot}' does not exist"));
+        }
+
+        client.query_one(
+            "SELECT pg_create_logical_replication_slot($1, 'wal2json')",
+            &[&slot],
+        )?;
+    }
+
+    let add_tables = if tables.is_empty() {
+        String::from("*.*")
+    } else {
+        tables.join(",")
+    };
+
+    while !shutdown.is_draining() {
+        // peek rather than get so that changes are only consumed once the workflow has run
+        let rows = client.query(
+            "SELECT lsn::text, data FROM pg_logical_slot_peek_changes(\
+                $1, NULL, $2, 'format-version', '2', 'include-timestamp', '1', 'add-tables', $3)",
+            &[&slot, &MAX_CHANGES_PER_POLL, &add_tables],
+        )?;
+
+        if rows.is_empty() {
+            std::thread::sleep(poll_interval);
+            continue;
+        }
+
+        for row in rows {
+            let lsn: String = row.get(0);
+            let data: String = row.get(1);
+
+            match decode_change(&lsn, &data)? {
+                Change::Row(change) => {
+                    let Err(e) = run(serde_json::to_vec(&change)?) else {
+                        continue;
+                    };
+
+                    // stop at the failure; the transaction is retried on the next poll
+                    let attempts = record_failure(failed, &lsn);
+                    if attempts < MAX_CHANGE_ATTEMPTS {
+                        return Err(anyhow!(
+                            "execution for change at {lsn} failed, attempt {attempts} of {MAX_CHANGE_ATTEMPTS}; {e}"
+                        ));
+                    }
+
+                    error!(
+                        "skipping change at {lsn} after {attempts} failed executions; {e}; change: {change}"
+                    );
+                    *failed = None;
+                }
+                Change::Commit => {
+                    client.query_one(
+                        "SELECT pg_replication_slot_advance($1, $2::text::pg_lsn)",
+                        &[&slot, &lsn],
+                    )?;
+                }
+                Change::Other => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Row change whose execution failed, and how many times in a row
+struct FailedChange {
+    lsn: String,
+    attempts: u32,
+}
+
+/// Counts a failed execution of the change at `lsn`, returning its attempts so far
+fn record_failure(failed: &mut Option<FailedChange>, lsn: &str) -> u32 {
+    let attempts = match failed {
+        Some(change) if change.lsn == lsn => change.attempts + 1,
+        _ => 1,
+    };
+    *failed = Some(FailedChange {
+        lsn: lsn.to_string(),
+        attempts,
+    });
+
+    attempts
+}
+
+enum Change {
+    Row(Value),
+    Commit,
+    Other,
+}
+
+/// Decodes a `wal2json` format version 2 record, adding its lsn to row changes
+fn decode_change(lsn: &str, data: &str) -> Result<Change> {
+    let mut change = serde_json::from_str::<Value>(data)?;
+
+    match change["action"].as_str() {
+        Some("I" | "U" | "D" | "T") => {
+            change["lsn"] = Value::String(lsn.to_string());
+            Ok(Change::Row(change))
+        }
+        Some("C") => Ok(Change::Commit),
+        _ => Ok(Change::Other),
+    }
+}
+
+fn parse_json_or_string(s: &str) -> Value {
+    serde_json::from_str(s).unwrap_or_else(|_| Value::String(s.to_string()))
+}
+
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_change() {
+        let change = decode_change(
+            "0/16B3748",
+            r#"{"action":"I","schema":"public","table":"orders","columns":[{"name":"id","type":"integer","value":1}]}"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            change,
+            Change::Row(value) if value["lsn"] == "0/16B3748" && value["table"] == "orders"
+        ));
+
+        assert!(matches!(
+            decode_change("0/16B3778", r#"{"action":"C"}"#).unwrap(),
+            Change::Commit
+        ));
+        assert!(matches!(
+            decode_change("0/16B3700", r#"{"action":"B"}"#).unwrap(),
+            Change::Other
+        ));
+    }
+
+    #[test]
+    fn test_new_context_per_input() {
+        let mut ids = vec![];
+        let mut run = with_new_context("postgres_notify:orders", |context, _| {
+            ids.push(context.id.clone());
+            Ok(())
+        });
+
+        run(b"1".to_vec()).unwrap();
+        run(b"2".to_vec()).unwrap();
+        drop(run);
+
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn test_record_failure() {
+        let mut failed = None;
+
+        assert_eq!(record_failure(&mut failed, "0/16B3748"), 1);
+        assert_eq!(record_failure(&mut failed, "0/16B3748"), 2);
+
+        // a different change starts over
+        assert_eq!(record_failure(&mut failed, "0/16B3778"), 1);
+    }
+
+    /// Url of a database with `wal_level = logical` and the `wal2json` plugin, for the tests
+    /// run with `cargo test -- --ignored`
+    fn test_url() -> String {
+        std::env::var("DEADLIFT_TEST_POSTGRES_URL")
+            .expect("DEADLIFT_TEST_POSTGRES_URL is required for postgres tests")
+    }
+
+    fn test_name() -> String {
+        format!("deadlift_test_{}", uuid::Uuid::new_v4().simple())
+    }
+
+    #[test]
+    #[ignore = "requires DEADLIFT_TEST_POSTGRES_URL"]
+    fn test_listen_delivers_notifications() {
+        let url = test_url();
+        let channel = test_name();
+        let (controller, shutdown) = crate::shutdown::shutdown_channel();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let listener = {
+            let (url, channel) = (url.clone(), channel.clone());
+            std::thread::spawn(move || {
+                listen(&url, &channel, &shutdown, |input| {
+                    tx.send(input)?;
+                    Ok(())
+                })
+            })
+        };
+
+        // notify until the listener has subscribed and received one
+        let mut client = Client::connect(&url, NoTls).unwrap();
+        let input = (0..50)
+            .find_map(|_| {
+                client
+                    .execute("SELECT pg_notify($1, $2)", &[&channel, &r#"{"id":1}"#])
+                    .unwrap();
+                rx.recv_timeout(Duration::from_millis(100)).ok()
+            })
+            .expect("no notification was delivered");
+
+        assert_eq!(
+            serde_json::from_slice::<Value>(&input).unwrap(),
+            json!({ "channel": channel, "payload": { "id": 1 } })
+        );
+
+        controller.drain();
+        listener.join().unwrap().unwrap();
+    }
+
+    #[test]
+    #[ignore = "requires DEADLIFT_TEST_POSTGRES_URL"]
+    fn test_replicate_peeks_and_advances_slot() {
+        let url = test_url();
+        let name = test_name();
+        let tables = vec![format!("public.{name}")];
+
+        let mut client = Client::connect(&url, NoTls).unwrap();
+        client
+            .batch_execute(&format!("CREATE TABLE {name} (id integer PRIMARY KEY)"))
+            .unwrap();
+        client
+            .query_one(
+                "SELECT pg_create_logical_replication_slot($1, 'wal2json')",
+                &[&name],
+            )
+            .unwrap();
+        client
+            .batch_execute(&format!("INSERT INTO {name} VALUES (1)"))
+            .unwrap();
+
+        let mut pending_changes = || {
+            client
+                .query(
+                    "SELECT data FROM pg_logical_slot_peek_changes(\
+                        $1, NULL, NULL, 'format-version', '2', 'add-tables', $2)",
+                    &[&name, &tables[0]],
+                )
+                .unwrap()
+                .into_iter()
+                .filter(|row| {
+                    matches!(
+                        decode_change("", &row.get::<_, String>(0)),
+                        Ok(Change::Row(_))
+                    )
+                })
+                .count()
+        };
+
+        // a failed run leaves the change on the slot
+        let (_controller, shutdown) = crate::shutdown::shutdown_channel();
+        let err = replicate(
+            &url,
+            &name,
+            false,
+            &tables,
+            Duration::from_millis(100),
+            &shutdown,
+            &mut None,
+            |_| Err(anyhow!("plugin trapped")),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("plugin trapped"));
+        assert_eq!(pending_changes(), 1);
+
+        // a successful run advances the slot past the transaction
+        let (controller, shutdown) = crate::shutdown::shutdown_channel();
+        let mut changes = vec![];
+        replicate(
+            &url,
+            &name,
+            false,
+            &tables,
+            Duration::from_millis(100),
+            &shutdown,
+            &mut None,
+            |input| {
+                changes.push(serde_json::from_slice::<Value>(&input)?);
+                controller.drain();
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0]["action"], "I");
+        assert_eq!(changes[0]["table"], name.as_str());
+        assert_eq!(pending_changes(), 0);
+
+        client
+            .query_one("SELECT pg_drop_replication_slot($1)", &[&name])
+            .unwrap();
+        client.batch_execute(&format!("DROP TABLE {name}")).unwrap();
+    }
+
+    #[test]
+    #[ignore = "requires DEADLIFT_TEST_POSTGRES_URL"]
+    fn test_replicate_skips_failing_change() {
+        let url = test_url();
+        let name = test_name();
+        let tables = vec![format!("public.{name}")];
+
+        let mut client = Client::connect(&url, NoTls).unwrap();
+        client
+            .batch_execute(&format!("CREATE TABLE {name} (id integer PRIMARY KEY)"))
+            .unwrap();
+        client
+            .query_one(
+                "SELECT pg_create_logical_replication_slot($1, 'wal2json')",
+                &[&name],
+            )
+            .unwrap();
+        client
+            .batch_execute(&format!("INSERT INTO {name} VALUES (1)"))
+            .unwrap();
+
+        let mut failed = None;
+        for attempt in 1..=MAX_CHANGE_ATTEMPTS {
+            let (controller, shutdown) = crate::shutdown::shutdown_channel();
+            let res = replicate(
+                &url,
+                &name,
+                false,
+                &tables,
+                Duration::from_millis(100),
+                &shutdown,
+                &mut failed,
+                |_| {
+                    // the last attempt skips the change, then returns once the slot advanced
+                    controller.drain();
+                    Err(anyhow!("plugin trapped"))
+                },
+            );
+
+            if attempt < MAX_CHANGE_ATTEMPTS {
+                assert!(res.unwrap_err().to_string().contains("plugin trapped"));
+            } else {
+                res.unwrap();
+            }
+        }
+
+        assert!(failed.is_none());
+        let pending = client
+            .query(
+                "SELECT data FROM pg_logical_slot_peek_changes(\
+                    $1, NULL, NULL, 'format-version', '2', 'add-tables', $2)",
+                &[&name, &tables[0]],
+            )
+            .unwrap()
+            .into_iter()
+            .filter(|row| {
+                matches!(
+                    decode_change("", &row.get::<_, String>(0)),
+                    Ok(Change::Row(_))
+                )
+            })
+            .count();
+        assert_eq!(pending, 0);
+
+        client
+            .query_one("SELECT pg_drop_replication_slot($1)", &[&name])
+            .unwrap();
+        client.batch_execute(&format!("DROP TABLE {name}")).unwrap();
+    }
+}
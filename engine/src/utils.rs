@@ -21,3 +21,58 @@ pub async fn get_or_create_object_store(
 }
 
 // TODO-- fix being able to upload the same object under the same name multiple times
+
+pub async fn get_or_create_object_store_with_config(
+    js: &async_nats::jetstream::Context,
+    config: async_nats::jetstream::object_store::Config,
+) -> anyhow::Result<async_nats::jetstream::object_store::ObjectStore> {
+    match js.get_object_store(&config.bucket).await {
+        Ok(store) => Ok(store),
+        Err(e) => {
+            if e.kind() == async_nats::jetstream::context::ObjectStoreErrorKind::GetStore {
+                js.create_object_store(async_nats::jetstream::object_store::Config {
+                    num_replicas: 1,
+                    ..config
+                })
+                .await
+                .map_err(anyhow::Error::from)
+            } else {
+                Err(anyhow::Error::from(e))
+            }
+        }
+    }
+}
+
+pub async fn get_or_create_key_value(
+    js: &async_nats::jetstream::Context,
+    config: async_nats::jetstream::kv::Config,
+) -> anyhow::Result<async_nats::jetstream::kv::Store> {
+    match js.get_key_value(config.bucket.clone()).await {
+        Ok(store) => Ok(store),
+        Err(e) => {
+            if e.kind() == async_nats::jetstream::context::KeyValueErrorKind::GetBucket {
+                js.create_key_value(async_nats::jetstream::kv::Config {
+                    num_replicas: 1,
+                    ..config
+                })
+                .await
+                .map_err(anyhow::Error::from)
+            } else {
+                Err(anyhow::Error::from(e))
+            }
+        }
+    }
+}
+
+/// Replaces characters that are not valid in key value keys, consumer names and queue groups
+pub fn to_name_token(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,650 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_nats::jetstream::object_store::ObjectStore;
+use futures::{StreamExt, TryStreamExt};
+use tokio::{io::AsyncReadExt, sync::watch, task::JoinHandle};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{EngineConfig, WorkflowConfig},
+    executor::Executor,
+    health::Health,
+    history::HistoryStore,
+    host::HostFunctions,
+    module::{load_workflow_modules, DefaultModuleLoader},
+    nats::start_execution_thread,
+    placement::is_placed,
+    plugin::new_plugin_pool,
+    registry::WorkflowRecord,
+    runtime::{supervise, FatalSender},
+    schedule::start_scheduler_thread,
+    shutdown::{Shutdown, ShutdownController, STOP_TIMEOUT},
+    subjects::Subjects,
+    trigger::start_trigger_threads,
+    utils::to_name_token,
+};
+
+/// Workflows an agent runs, by name; each has its own executor, plugin pool and threads
+pub struct Workflows {
+    loaded: RwLock<HashMap<String, LoadedWorkflow>>,
+
+    /// Tasks draining replaced and unloaded workflows
+    unloading: Mutex<Vec<JoinHandle<()>>>,
+    changes: watch::Sender<()>,
+}
+
+struct LoadedWorkflow {
+    executor: Arc<Executor>,
+    record: WorkflowRecord,
+
+    /// Digest of the workflow object, so that unchanged puts are not reloaded
+    digest: Option<String>,
+    shutdown: ShutdownController,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Default for Workflows {
+    fn default() -> Self {
+        Self {
+            loaded: RwLock::new(HashMap::new()),
+            unloading: Mutex::new(vec![]),
+            changes: watch::channel(()).0,
+        }
+    }
+}
+
+impl Workflows {
+    pub fn get(&self, name: &str) -> Option<Arc<Executor>> {
+        self.loaded
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|loaded| loaded.executor.clone())
+    }
+
+    /// Finds a workflow by its name as a subject token
+    pub fn get_by_token(&self, token: &str) -> Option<Arc<Executor>> {
+        self.loaded
+            .read()
+            .unwrap()
+            .values()
+            .find(|loaded| to_name_token(&loaded.executor.workflow().name) == token)
+            .map(|loaded| loaded.executor.clone())
+    }
+
+    /// Executors of every workflow, by name
+    pub fn executors(&self) -> Vec<Arc<Executor>> {
+        let mut executors = self
+            .loaded
+            .read()
+            .unwrap()
+            .values()
+            .map(|loaded| loaded.executor.clone())
+            .collect::<Vec<_>>();
+        executors.sort_by(|a, b| a.workflow().name.cmp(&b.workflow().name));
+
+        executors
+    }
+
+    /// Workflows as published in agent heartbeats, by name
+    pub fn records(&self) -> Vec<WorkflowRecord> {
+        let mut records = self
+            .loaded
+            .read()
+            .unwrap()
+            .values()
+            .map(|loaded| loaded.record.clone())
+            .collect::<Vec<_>>();
+        records.sort_by(|a, b| a.name.cmp(&b.name));
+
+        records
+    }
+
+    /// Notified whenever a workflow is loaded or unloaded
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    /// Fails if another loaded workflow has the same subject token as `name`, as they would share
+    /// execution subjects and service endpoints
+    fn check_token(&self, name: &str) -> Result<()> {
+        let loaded = self.loaded.read().unwrap();
+
+        match token_conflict(loaded.keys().map(String::as_str), name) {
+            Some(other) => Err(anyhow!(
+                "workflow '{name}' has the same subject token '{}' as loaded workflow '{other}'; rename one of them",
+                to_name_token(name)
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn is_current(&self, name: &str, digest: Option<&str>) -> bool {
+        digest.is_some()
+            && self
+                .loaded
+                .read()
+                .unwrap()
+                .get(name)
+                .is_some_and(|loaded| loaded.digest.as_deref() == digest)
+    }
+
+    /// Loads a workflow, returning the version it replaces
+    fn insert(&self, loaded: LoadedWorkflow) -> Option<LoadedWorkflow> {
+        let previous = self
+            .loaded
+            .write()
+            .unwrap()
+            .insert(loaded.record.name.clone(), loaded);
+        self.changes.send_replace(());
+
+        previous
+    }
+
+    fn remove(&self, name: &str) -> Option<LoadedWorkflow> {
+        let removed = self.loaded.write().unwrap().remove(name);
+        if removed.is_some() {
+            self.changes.send_replace(());
+        }
+
+        removed
+    }
+
+    /// Takes the thread handles of every workflow, and the tasks still draining unloaded ones,
+    /// to wait for them while shutting down
+    pub(crate) fn take_handles(&self) -> Vec<JoinHandle<()>> {
+        let mut handles = std::mem::take(&mut *self.unloading.lock().unwrap());
+        handles.extend(
+            self.loaded
+                .write()
+                .unwrap()
+                .values_mut()
+                .flat_map(|loaded| std::mem::take(&mut loaded.handles)),
+        );
+
+        handles
+    }
+
+    pub(crate) fn abort(&self) {
+        for loaded in self.loaded.read().unwrap().values() {
+            for handle in &loaded.handles {
+                handle.abort();
+            }
+        }
+        for handle in self.unloading.lock().unwrap().iter() {
+            handle.abort();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "http"))]
+impl Workflows {
+    /// Loads an executor without starting any threads
+    pub(crate) fn insert_executor(&self, executor: Arc<Executor>) {
+        self.insert(LoadedWorkflow {
+            record: WorkflowRecord::new(executor.workflow(), &[]),
+            executor,
+            digest: None,
+            shutdown: crate::shutdown::shutdown_channel().0,
+            handles: vec![],
+        });
+    }
+}
+
+/// Loaded workflow, other than `name` itself, whose name maps to the same subject token
+fn token_conflict<'a>(mut loaded: impl Iterator<Item = &'a str>, name: &str) -> Option<&'a str> {
+    let token = to_name_token(name);
+
+    loaded.find(|other| *other != name && to_name_token(other) == token)
+}
+
+/// How an agent's config selects a workflow
+#[derive(Debug, PartialEq, Eq)]
+enum Selection {
+    /// Named by `workflow.name` or in `workflows`; loaded even when placed on other agents
+    Named,
+
+    /// Matched by a pattern in `workflows`, or by an agent that names no workflows
+    Matched,
+    Unselected,
+}
+
+fn select(name: &str, config: &EngineConfig) -> Selection {
+    if (!config.workflow.name.is_empty() && config.workflow.name == name)
+        || config.workflows.iter().any(|pattern| pattern == name)
+    {
+        Selection::Named
+    } else if config
+        .workflows
+        .iter()
+        .any(|pattern| matches_pattern(pattern, name))
+        || (config.workflow.name.is_empty() && config.workflows.is_empty())
+    {
+        Selection::Matched
+    } else {
+        Selection::Unselected
+    }
+}
+
+/// Whether the agent runs a workflow; matched workflows must be placed on it
+fn is_selected(workflow: &WorkflowConfig, config: &EngineConfig) -> Result<bool> {
+    match select(&workflow.name, config) {
+        Selection::Named => {
+            if !is_placed(&workflow.placement, &config.agent.labels)? {
+                warn!(
+                    "agent labels don't satisfy the placement of workflow '{}'; loading it as configured",
+                    workflow.name
+                );
+            }
+
+            Ok(true)
+        }
+        Selection::Matched => is_placed(&workflow.placement, &config.agent.labels),
+        Selection::Unselected => Ok(false),
+    }
+}
+
+/// Matches a workflow name against a pattern, where `*` matches any run of characters and `?`
+/// a single one
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    let (mut p, mut n) = (0, 0);
+    // pattern position after the last `*`, and the name position it currently matches up to
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Reads the workflows an agent runs, with the digests of their objects
+///
+/// Fails if the workflow named by `workflow.name` is not published.
+pub(crate) async fn read_agent_workflows(
+    bucket: &ObjectStore,
+    config: &EngineConfig,
+) -> Result<Vec<(WorkflowConfig, Option<String>)>> {
+    let objects = bucket
+        .list()
+        .await?
+        .try_filter(|info| futures::future::ready(!info.deleted))
+        .map_ok(|info| (info.name, info.digest))
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let mut workflows = vec![];
+    for (name, digest) in objects {
+        let selection = select(&name, config);
+        if selection == Selection::Unselected {
+            continue;
+        }
+
+        let workflow = match read_workflow(bucket, &name).await {
+            Ok(workflow) => workflow,
+            Err(e) if selection == Selection::Named => return Err(e),
+            Err(e) => {
+                warn!("skipping workflow '{name}'; {e}");
+                continue;
+            }
+        };
+
+        match is_selected(&workflow, config) {
+            Ok(true) => workflows.push((workflow, digest)),
+            Ok(false) => info!("workflow '{name}' is placed on other agents"),
+            Err(e) => warn!("skipping workflow '{name}'; {e}"),
+        }
+    }
+
+    if !config.workflow.name.is_empty()
+        && !workflows
+            .iter()
+            .any(|(workflow, _)| workflow.name == config.workflow.name)
+    {
+        return Err(anyhow!(
+            "workflow '{}' is not published",
+            config.workflow.name
+        ));
+    }
+
+    if workflows.is_empty() {
+        warn!("no workflow is placed on this agent");
+    }
+
+    Ok(workflows)
+}
+
+async fn read_workflow(bucket: &ObjectStore, name: &str) -> Result<WorkflowConfig> {
+    let mut object = bucket.get(name).await?;
+
+    let mut bytes = vec![];
+    object.read_to_end(&mut bytes).await?;
+
+    serde_yaml::from_slice::<WorkflowConfig>(&bytes)
+        .map_err(|e| anyhow!("invalid workflow '{name}'; {e}"))
+}
+
+/// Loads the modules of a workflow into its own plugin pool
+pub(crate) async fn load_workflow(
+    workflow: WorkflowConfig,
+    loader: &DefaultModuleLoader,
+    config: &EngineConfig,
+    history: Option<&HistoryStore>,
+) -> Result<(Arc<Executor>, WorkflowRecord)> {
+    let modules = load_workflow_modules(&workflow, loader).await?;
+    let record = WorkflowRecord::new(&workflow, &modules);
+    let host_functions = HostFunctions::new(&config.postgres, &workflow);
+    let pool = new_plugin_pool(modules, &config.plugin, &host_functions);
+
+    let mut executor = Executor::new(workflow, pool)?;
+    if let Some(history) = history {
+        executor = executor.with_history(history.clone());
+    }
+
+    Ok((Arc::new(executor), record))
+}
+
+/// Loads, starts and unloads the workflows of an agent
+pub(crate) struct WorkflowManager {
+    pub(crate) workflows: Arc<Workflows>,
+    pub(crate) nc: async_nats::Client,
+    pub(crate) js: async_nats::jetstream::Context,
+    pub(crate) loader: DefaultModuleLoader,
+    pub(crate) config: EngineConfig,
+    pub(crate) history: Option<HistoryStore>,
+    pub(crate) health: Arc<Health>,
+    pub(crate) shutdown: Shutdown,
+    pub(crate) fatal: FatalSender,
+}
+
+impl WorkflowManager {
+    /// Loads a workflow and starts its threads; a loaded version of it is drained once the new
+    /// one runs, and keeps running if the new one fails to load or start
+    pub(crate) async fn start(
+        &self,
+        workflow: WorkflowConfig,
+        digest: Option<String>,
+    ) -> Result<()> {
+        self.workflows.check_token(&workflow.name)?;
+
+        let (executor, record) =
+            load_workflow(workflow, &self.loader, &self.config, self.history.as_ref()).await?;
+
+        let (controller, shutdown) = self.shutdown.child();
+        let handles = match self.start_threads(executor.clone(), shutdown).await {
+            Ok(handles) => handles,
+            Err(e) => {
+                // threads that did start end once draining
+                controller.drain();
+                return Err(e);
+            }
+        };
+
+        info!(
+            "loaded version {} of workflow '{}'",
+            record.version, record.name
+        );
+
+        let previous = self.workflows.insert(LoadedWorkflow {
+            executor,
+            record,
+            digest,
+            shutdown: controller,
+            handles,
+        });
+        if let Some(previous) = previous {
+            self.unload(previous);
+        }
+
+        Ok(())
+    }
+
+    /// Unloads a workflow, draining its executions in the background
+    pub(crate) fn stop(&self, name: &str) {
+        if let Some(loaded) = self.workflows.remove(name) {
+            self.unload(loaded);
+            info!("unloading workflow '{name}'");
+        }
+    }
+
+    /// Drains a workflow's executions in a task, so that other workflows keep loading meanwhile
+    fn unload(&self, mut loaded: LoadedWorkflow) {
+        let grace_period = Duration::from_secs(self.config.shutdown.grace_period_secs);
+
+        let handle = tokio::task::spawn(async move {
+            loaded.shutdown.drain();
+
+            if tokio::time::timeout(
+                grace_period,
+                futures::future::join_all(loaded.handles.iter_mut()),
+            )
+            .await
+            .is_err()
+            {
+                warn!(
+                    "grace period elapsed; abandoning unfinished executions of workflow '{}'",
+                    loaded.record.name
+                );
+                loaded.shutdown.stop();
+
+                let _ = tokio::time::timeout(
+                    STOP_TIMEOUT,
+                    futures::future::join_all(loaded.handles.iter_mut()),
+                )
+                .await;
+            }
+
+            for handle in &loaded.handles {
+                handle.abort();
+            }
+
+            // dropping the last executor drops its plugin pool, which blocks on wasm instances
+            let executor = loaded.executor;
+            if let Err(e) = tokio::task::spawn_blocking(move || drop(executor)).await {
+                error!("failed to unload workflow '{}'; {e}", loaded.record.name);
+            }
+        });
+
+        let mut unloading = self.workflows.unloading.lock().unwrap();
+        unloading.retain(|handle| !handle.is_finished());
+        unloading.push(handle);
+    }
+
+    /// Starts the supervised execution, schedule and trigger threads of a workflow
+    async fn start_threads(
+        &self,
+        executor: Arc<Executor>,
+        shutdown: Shutdown,
+    ) -> Result<Vec<JoinHandle<()>>> {
+        let name = executor.workflow().name.clone();
+        let mut handles = vec![];
+
+        if self.config.nats.enable_execution_thread {
+            handles.push(
+                supervise(
+                    format!("execution of '{name}'"),
+                    {
+                        let nc = self.nc.clone();
+                        let executor = executor.clone();
+                        let subjects = Subjects::new(&self.config.nats);
+                        let health = self.health.clone();
+                        let shutdown = shutdown.clone();
+
+                        move || {
+                            start_execution_thread(
+                                nc.clone(),
+                                executor.clone(),
+                                subjects.clone(),
+                                health.clone(),
+                                shutdown.clone(),
+                            )
+                        }
+                    },
+                    shutdown.clone(),
+                    self.fatal.clone(),
+                )
+                .await?,
+            );
+        }
+
+        if !executor.workflow().schedules.is_empty() {
+            handles.push(
+                supervise(
+                    format!("scheduler of '{name}'"),
+                    {
+                        let js = self.js.clone();
+                        let executor = executor.clone();
+                        let shutdown = shutdown.clone();
+
+                        move || {
+                            start_scheduler_thread(js.clone(), executor.clone(), shutdown.clone())
+                        }
+                    },
+                    shutdown.clone(),
+                    self.fatal.clone(),
+                )
+                .await?,
+            );
+        }
+
+        handles.extend(
+            start_trigger_threads(self.nc.clone(), executor, shutdown, self.fatal.clone()).await?,
+        );
+
+        Ok(handles)
+    }
+}
+
+/// Follows the workflow bucket: loads workflows published for the agent, reloads changed ones
+/// and unloads deleted ones or those no longer placed on it
+pub(crate) async fn start_workflow_watcher_thread(
+    bucket: ObjectStore,
+    manager: Arc<WorkflowManager>,
+) -> Result<JoinHandle<()>> {
+    Ok(tokio::task::spawn(async move {
+        // the latest version of every object is replayed first; loaded ones are skipped by digest
+        let updates = match bucket.watch_with_history().await {
+            Ok(watch) => watch.take_until(manager.shutdown.draining()),
+            Err(e) => {
+                error!("failed to watch workflows; {e}");
+                return;
+            }
+        };
+        tokio::pin!(updates);
+
+        while let Some(update) = updates.next().await {
+            let info = match update {
+                Ok(info) => info,
+                Err(e) => {
+                    error!("failed to watch workflows; {e}");
+                    return;
+                }
+            };
+
+            if info.deleted {
+                manager.stop(&info.name);
+                continue;
+            }
+
+            if select(&info.name, &manager.config) == Selection::Unselected
+                || manager
+                    .workflows
+                    .is_current(&info.name, info.digest.as_deref())
+            {
+                continue;
+            }
+
+            let workflow = match read_workflow(&bucket, &info.name).await {
+                Ok(workflow) => workflow,
+                Err(e) => {
+                    warn!("skipping workflow '{}'; {e}", info.name);
+                    continue;
+                }
+            };
+
+            let res = match is_selected(&workflow, &manager.config) {
+                Ok(true) => manager.start(workflow, info.digest).await,
+                Ok(false) => {
+                    manager.stop(&info.name);
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = res {
+                error!("failed to load workflow '{}'; {e}", info.name);
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_conflict() {
+        let loaded = ["do some math", "billing"];
+
+        assert_eq!(
+            token_conflict(loaded.into_iter(), "do_some_math"),
+            Some("do some math")
+        );
+        // reloading a workflow doesn't conflict with itself
+        assert_eq!(token_conflict(loaded.into_iter(), "do some math"), None);
+        assert_eq!(token_conflict(loaded.into_iter(), "orders"), None);
+    }
+
+    #[test]
+    fn test_select() {
+        assert!(matches_pattern("sync *", "sync store inventory"));
+        assert!(matches_pattern("*-v?", "billing-v2"));
+        assert!(matches_pattern("*", ""));
+        assert!(!matches_pattern("sync *", "billing"));
+        assert!(!matches_pattern("billing-v?", "billing-v10"));
+
+        let mut config = serde_yaml::from_str::<EngineConfig>(
+            "
+            nats:
+                url: localhost:4222
+            plugin:
+                wasi: true
+                allowed_hosts: []
+            ",
+        )
+        .unwrap();
+        assert_eq!(select("billing", &config), Selection::Matched);
+
+        config.workflow.name = String::from("billing");
+        config.workflows = vec![String::from("reports"), String::from("sync *")];
+        assert_eq!(select("billing", &config), Selection::Named);
+        assert_eq!(select("reports", &config), Selection::Named);
+        assert_eq!(select("sync orders", &config), Selection::Matched);
+        assert_eq!(select("math", &config), Selection::Unselected);
+    }
+}